@@ -0,0 +1,220 @@
+// Generated from the Rust API types by `cargo run -p backend --bin schema`, do not edit
+
+export type CityRequest =
+    | CitySearchRequest
+    | ClimateSearchRequest
+    | CityGetRequest
+    | CitiesGetRequest
+
+/**
+ * Example:
+ * `{"command": "searchCity", "query": "Tokyo", "startIndex": 0, "maxItems": 4}`
+ */
+export type CitySearchRequest = {
+    command: 'searchCity'
+    query: string
+    startIndex?: number | null
+    maxItems?: number | null
+    /** If set, each item also has `city` with only these fields, see `Projection` */
+    fields?: string[] | null
+    /**
+     * For a search box: cities with a name starting with `query` first, the most populous first, then
+     * only if there are too few of them, other matches by similarity
+     */
+    autocomplete?: boolean | null
+}
+
+/**
+ * Example:
+ * `{"command": "searchClimate", "cityId": 34040, "startIndex": 0, "maxItems": 5}`
+ */
+export type ClimateSearchRequest = {
+    command: 'searchClimate'
+    cityId: number
+    startIndex?: number | null
+    maxItems?: number | null
+    /**
+     * If set, `city` of each item has only these fields, e.g. `["names[0]", "climate.tmaxMonthly"]`.
+     * Indexes pick elements in the given order without keeping their positions, so `climate.tmaxMonthly[6]`
+     * gives a one-element array.
+     */
+    fields?: string[] | null
+}
+
+/**
+ * Example:
+ * `{"command": "getCity", "id": 14823}`
+ */
+export type CityGetRequest = {
+    command: 'getCity'
+    id: number
+}
+
+/**
+ * Example:
+ * `{"command": "getCities", "ids": [14823, 16709]}`
+ */
+export type CitiesGetRequest = {
+    command: 'getCities'
+    ids: number[]
+}
+
+/**
+ * Batch request is a JSON array of `CityRequest`s, for example:
+ * `[{"command": "searchCity", "query": "Tokyo"}, {"command": "getCity", "id": 14823}]`.
+ * The response is an array of the same length and order, where each element is
+ * either a `CityResponse` or an error object, see `ApiError`.
+ */
+export type CityResponse =
+    | CitySearchResponse & {
+        command: 'searchCity'
+    }
+    | ClimateSearchResponse & {
+        command: 'searchClimate'
+    }
+    | CityGetResponse & {
+        command: 'getCity'
+    }
+    | CitiesGetResponse & {
+        command: 'getCities'
+    }
+
+export type CitySearchResponseItem = {
+    id: number
+    score: number
+    matchedName: string
+    name: string
+    population: number
+    adminUnit: string | null
+    country: string
+    /** Only if the request has `fields` */
+    city?: ProjectedCity | null
+}
+
+/** The whole city, or only the requested fields of it */
+export type ProjectedCity =
+    | City
+    | PartialCity
+
+export type City = {
+    names: string[]
+    latitude: number
+    longitude: number
+    adminUnit: string | null
+    country: string
+    population: number
+    elevation: number | null
+    region: string
+    modificationDate: string
+    climate: CityClimate
+}
+
+export type CityClimate = {
+    humidityMonthly: [number | null, number | null, number | null, number | null, number | null, number | null, number | null, number | null, number | null, number | null, number | null, number | null]
+    pptMonthly: [number, number, number, number, number, number, number, number, number, number, number, number]
+    sradMonthly: [number, number, number, number, number, number, number, number, number, number, number, number]
+    tmaxMonthly: [number, number, number, number, number, number, number, number, number, number, number, number]
+    tminMonthly: [number, number, number, number, number, number, number, number, number, number, number, number]
+    wsMonthly: [number, number, number, number, number, number, number, number, number, number, number, number]
+}
+
+export type PartialCity = {
+    names?: string[]
+    latitude?: number
+    longitude?: number
+    adminUnit?: string | null
+    country?: string
+    population?: number
+    elevation?: number | null
+    region?: string
+    modificationDate?: string
+    climate?: {
+        humidityMonthly?: (number | null)[]
+        pptMonthly?: number[]
+        sradMonthly?: number[]
+        tmaxMonthly?: number[]
+        tminMonthly?: number[]
+        wsMonthly?: number[]
+    }
+}
+
+export type CitySearchResponse = {
+    items: CitySearchResponseItem[]
+    elapsedMs: number
+    cacheHitRatePercent: number
+    /**
+     * Percentage of the city names which were ruled out by their characters without computing their
+     * similarity to the query, averaged over the ways to split the query into a name and the rest
+     */
+    prunedPercent: number
+    /** The time budget ran out before all cities were scored, so some matches may be missing */
+    truncated: boolean
+}
+
+export type ClimateSearchResponseItem = {
+    id: number
+    city: ProjectedCity
+    distanceKm: number
+    similarityPercent: number
+}
+
+export type ClimateSearchResponse = {
+    items: ClimateSearchResponseItem[]
+    elapsedMs: number
+    /** The time budget ran out, so the items are not necessarily the most similar ones */
+    truncated: boolean
+}
+
+export type CityGetResponse = {
+    id: number
+    city: City
+}
+
+export type CitiesGetResponse = {
+    /** Same order as in the request */
+    items: CityGetResponse[]
+}
+
+/** Sent over the `GET /cities/stream` WebSocket, only for the latest `searchCity` message */
+export type CitySearchStreamResponse = {
+    /** `query` of the message this answers */
+    query: string
+    /** Best matches among the cities scanned so far; the complete results follow */
+    partial: boolean
+    items: CitySearchResponseItem[]
+    elapsedMs: number
+    cacheHitRatePercent: number
+    /**
+     * Percentage of the city names which were ruled out by their characters without computing their
+     * similarity to the query, averaged over the ways to split the query into a name and the rest
+     */
+    prunedPercent: number
+    /** The time budget ran out before all cities were scored, so some matches may be missing */
+    truncated: boolean
+}
+
+export type ErrorResponse = {
+    error: ApiError
+}
+
+/** Serialized as `{"error": {"code": "unknownField", "message": "...", "field": "maxitems"}}` */
+export type ApiError = {
+    code: ErrorCode
+    message: string
+    field?: string | null
+}
+
+export type ErrorCode =
+    | 'unknownCommand'
+    | 'unknownField'
+    | 'cityNotFound'
+    | 'methodNotAllowed'
+    | 'internal'
+    | 'badJson'
+    | 'limitExceeded'
+    | 'notFound'
+    | 'upgradeRequired'
+    | 'unauthorized'
+    | 'notReady'
+    | 'timeout'
+    | 'tooManyRequests'
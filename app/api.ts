@@ -1,3 +1,7 @@
+import type { City, CityRequest, CityResponse, CitySearchRequest, CitySearchStreamResponse, ErrorResponse, PartialCity } from './api.generated.ts'
+
+export type * from './api.generated.ts'
+
 export async function fetchApi<Req extends CityRequest>(request: Req): Promise<GetResponseType<Req>> {
     return Promise.race([
         fetchApiImpl(request),
@@ -14,96 +18,47 @@ async function fetchApiImpl<Req extends CityRequest>(request: Req): Promise<GetR
     return await res.json();
 }
 
-type GetResponseType<R extends CityRequest> =
-    R extends CitySearchRequest ? CitySearchResponse
-    : R extends ClimateSearchRequest ? ClimateSearchResponse
-    : never
-
-export type CityRequest =
-    | CitySearchRequest
-    | ClimateSearchRequest
-
-export type CitySearchRequest = {
-    command: 'searchCity'
-    query: string
-    startIndex?: number
-    maxItems?: number
-}
-
-export type ClimateSearchRequest = {
-    command: 'searchClimate'
-    cityId: number
-    startIndex?: number
-    maxItems?: number
-}
-
-export type CityResponse =
-    | CitySearchResponse
-    | ClimateSearchResponse
-
-export type CitySearchResponse = {
-    command: 'searchCity'
-    items: CitySearchResponseItem[]
-    elapsedMs: number
-    cacheHitRatePercent: number
-}
-
-export type CitySearchResponseItem = {
-    id: number
-    score: number
-    matchedName: string
-    name: string
-    population: number
-    adminUnit: string | null
-    country: string
+export async function fetchApiBatch<Reqs extends CityRequest[]>(requests: [...Reqs]): Promise<{ [K in keyof Reqs]: GetResponseType<Reqs[K]> | ErrorResponse }> {
+    const req = new Request('http://localhost:3001', {
+        method: 'POST',
+        body: JSON.stringify(requests),
+    })
+    const res = await fetch(req);
+    return await res.json();
 }
 
-export type ClimateSearchResponse = {
-    command: 'searchClimate'
-    items: ClimateSearchResponseItem[]
-    elapsedMs: number
+/**
+ * Searches as you type over the `/cities/stream` WebSocket. Each `search` supersedes the previous one,
+ * and `onMessage` gets the partial and complete results of the latest query only.
+ */
+export function openCitySearchStream(
+    onMessage: (message: WholeCities<CitySearchStreamResponse> | ErrorResponse) => void,
+): { search: (request: Omit<CitySearchRequest, 'command'>) => void, close: () => void } {
+    const ws = new WebSocket('ws://localhost:3001/cities/stream')
+    let pending: string | undefined
+    ws.onopen = () => {
+        if (pending !== undefined) ws.send(pending)
+        pending = undefined
+    }
+    ws.onmessage = event => onMessage(JSON.parse(event.data))
+    return {
+        search: request => {
+            const message = JSON.stringify({ command: 'searchCity', ...request } satisfies CitySearchRequest)
+            if (ws.readyState === WebSocket.CONNECTING) {
+                pending = message
+            } else {
+                ws.send(message)
+            }
+        },
+        close: () => ws.close(),
+    }
 }
 
-export type ClimateSearchResponseItem = {
-    id: number
-    city: City
-    distanceKm: number
-    similarityPercent: number
-}
+type GetResponseType<R extends CityRequest> = WithWholeCities<R, Extract<CityResponse, { command: R['command'] }>>
 
-export type City = {
-    names: string[]
-    latitude: number
-    longitude: number
-    adminUnit: string | null
-    country: string
-    population: number
-    elevation: number | null
-    region: string
-    modificationDate: string
-    climate: CityClimate
-}
+/** Without `fields`, each `ProjectedCity` is a whole `City` */
+type WithWholeCities<R, T> = R extends { fields: string[] } ? T : WholeCities<T>
 
-export type CityClimate = {
-    humidityMonthly: number[]
-    pptMonthly: Monthly
-    sradMonthly: Monthly
-    tmaxMonthly: Monthly
-    tminMonthly: Monthly
-    wsMonthly: Monthly
-}
+type WholeCities<T> = T extends PartialCity ? PartialCity extends T ? City : WholeCitiesIn<T> : WholeCitiesIn<T>
 
-export type Monthly = [
-    jan: number,
-    feb: number,
-    mar: number,
-    apr: number,
-    may: number,
-    jun: number,
-    jul: number,
-    aug: number,
-    sep: number,
-    oct: number,
-    nov: number,
-    dec: number,
-]
+type WholeCitiesIn<T> = T extends object ? { [K in keyof T]: WholeCities<T[K]> } : T
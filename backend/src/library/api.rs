@@ -1,21 +1,43 @@
 use common::city::City;
+use schemars::{json_schema, JsonSchema, Schema, SchemaGenerator};
+use std::borrow::Cow;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
 #[serde(tag = "command", rename_all = "camelCase")]
 pub enum CityRequest {
     SearchCity(CitySearchRequest),
     SearchClimate(ClimateSearchRequest),
+    GetCity(CityGetRequest),
+    GetCities(CitiesGetRequest),
+}
+
+impl CityRequest {
+    /// Value of the `command` tag
+    pub fn command(&self) -> &'static str {
+        match self {
+            CityRequest::SearchCity(_) => "searchCity",
+            CityRequest::SearchClimate(_) => "searchClimate",
+            CityRequest::GetCity(_) => "getCity",
+            CityRequest::GetCities(_) => "getCities",
+        }
+    }
 }
 
 /// Example:
 /// `{"command": "searchCity", "query": "Tokyo", "startIndex": 0, "maxItems": 4}`
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[derive(Debug, PartialEq, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+#[schemars(title = "CitySearchRequest")]
 pub struct CitySearchRequest {
     pub query: String,
     pub start_index: Option<usize>,
     pub max_items: Option<usize>,
+    /// If set, each item also has `city` with only these fields, see `Projection`
+    pub fields: Option<Vec<String>>,
+    /// For a search box: cities with a name starting with `query` first, the most populous first, then
+    /// only if there are too few of them, other matches by similarity
+    pub autocomplete: Option<bool>,
 }
 
 pub const SEARCH_DEFAULT_START_INDEX: usize = 0;
@@ -23,33 +45,67 @@ pub const SEARCH_DEFAULT_MAX_ITEMS: usize = 10;
 
 /// Example:
 /// `{"command": "searchClimate", "cityId": 34040, "startIndex": 0, "maxItems": 5}`
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[derive(Debug, PartialEq, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+#[schemars(title = "ClimateSearchRequest")]
 pub struct ClimateSearchRequest {
     pub city_id: usize,
     pub start_index: Option<usize>,
     pub max_items: Option<usize>,
+    /// If set, `city` of each item has only these fields, e.g. `["names[0]", "climate.tmaxMonthly"]`.
+    /// Indexes pick elements in the given order without keeping their positions, so `climate.tmaxMonthly[6]`
+    /// gives a one-element array.
+    pub fields: Option<Vec<String>>,
 }
 
 pub const CLIMATE_DEFAULT_START_INDEX: usize = 0;
 pub const CLIMATE_DEFAULT_MAX_ITEMS: usize = 100;
 
-#[derive(Debug, Serialize)]
+/// Example:
+/// `{"command": "getCity", "id": 14823}`
+#[derive(Debug, PartialEq, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+#[schemars(title = "CityGetRequest")]
+pub struct CityGetRequest {
+    pub id: usize,
+}
+
+/// Example:
+/// `{"command": "getCities", "ids": [14823, 16709]}`
+#[derive(Debug, PartialEq, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+#[schemars(title = "CitiesGetRequest")]
+pub struct CitiesGetRequest {
+    pub ids: Vec<usize>,
+}
+
+/// Batch request is a JSON array of `CityRequest`s, for example:
+/// `[{"command": "searchCity", "query": "Tokyo"}, {"command": "getCity", "id": 14823}]`.
+/// The response is an array of the same length and order, where each element is
+/// either a `CityResponse` or an error object, see `ApiError`.
+#[derive(Debug, Serialize, JsonSchema)]
 #[serde(tag = "command", rename_all = "camelCase")]
 pub enum CityResponse<'a> {
     SearchCity(CitySearchResponse<'a>),
     SearchClimate(ClimateSearchResponse<'a>),
+    GetCity(CityGetResponse<'a>),
+    GetCities(CitiesGetResponse<'a>),
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct CitySearchResponse<'a> {
     pub items: Vec<CitySearchResponseItem<'a>>,
     pub elapsed_ms: u32,
     pub cache_hit_rate_percent: f32,
+    /// Percentage of the city names which were ruled out by their characters without computing their
+    /// similarity to the query, averaged over the ways to split the query into a name and the rest
+    pub pruned_percent: f32,
+    /// The time budget ran out before all cities were scored, so some matches may be missing
+    pub truncated: bool,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct CitySearchResponseItem<'a> {
     pub id: usize,
@@ -59,21 +115,100 @@ pub struct CitySearchResponseItem<'a> {
     pub population: u64,
     pub admin_unit: &'a Option<String>,
     pub country: &'a str,
+    /// Only if the request has `fields`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub city: Option<ProjectedCity<'a>>,
 }
 
+/// Sent over the `GET /cities/stream` WebSocket, only for the latest `searchCity` message
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CitySearchStreamResponse<'a> {
+    /// `query` of the message this answers
+    pub query: &'a str,
+    /// Best matches among the cities scanned so far; the complete results follow
+    pub partial: bool,
+    #[serde(flatten)]
+    pub response: CitySearchResponse<'a>,
+}
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct ClimateSearchResponse<'a> {
     pub items: Vec<ClimateSearchResponseItem<'a>>,
     pub elapsed_ms: u32,
+    /// The time budget ran out, so the items are not necessarily the most similar ones
+    pub truncated: bool,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct ClimateSearchResponseItem<'a> {
     pub id: usize,
-    pub city: &'a City,
+    pub city: ProjectedCity<'a>,
     pub distance_km: f64,
     pub similarity_percent: f32,
 }
+
+/// The whole city, or only the requested fields of it
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum ProjectedCity<'a> {
+    Full(&'a City),
+    Fields(serde_json::Value),
+}
+
+/// `City`, or `PartialCity` where every field is optional and arrays may be shorter
+impl JsonSchema for ProjectedCity<'_> {
+    fn schema_name() -> Cow<'static, str> {
+        "ProjectedCity".into()
+    }
+
+    fn json_schema(generator: &mut SchemaGenerator) -> Schema {
+        let full = generator.subschema_for::<City>();
+        let mut partial = City::json_schema(generator);
+        make_partial(partial.as_object_mut().unwrap(), generator);
+        generator.definitions_mut().insert("PartialCity".into(), partial.into());
+        let partial_ref = format!("#{}/PartialCity", generator.settings().definitions_path);
+        json_schema!({
+            "description": "The whole city, or only the requested fields of it",
+            "anyOf": [full, { "$ref": partial_ref }]
+        })
+    }
+}
+
+/// Drops `required` and array length bounds, recursively, inlining referenced objects
+fn make_partial(schema: &mut serde_json::Map<String, serde_json::Value>, generator: &SchemaGenerator) {
+    schema.remove("required");
+    schema.remove("minItems");
+    schema.remove("maxItems");
+    if let Some(reference) = schema.get("$ref").and_then(|it| it.as_str()) {
+        let name = reference.rsplit('/').next().unwrap();
+        if let Some(serde_json::Value::Object(definition)) = generator.definitions().get(name) {
+            let mut definition = definition.clone();
+            make_partial(&mut definition, generator);
+            *schema = definition;
+        }
+    }
+    if let Some(serde_json::Value::Object(properties)) = schema.get_mut("properties") {
+        for property in properties.values_mut() {
+            if let serde_json::Value::Object(property) = property {
+                make_partial(property, generator);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CityGetResponse<'a> {
+    pub id: usize,
+    pub city: &'a City,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CitiesGetResponse<'a> {
+    /// Same order as in the request
+    pub items: Vec<CityGetResponse<'a>>,
+}
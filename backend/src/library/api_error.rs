@@ -0,0 +1,154 @@
+use hyper::StatusCode;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum ErrorCode {
+    /// Malformed JSON (or MessagePack, CBOR), wrong value type, missing field
+    BadJson,
+    UnknownCommand,
+    UnknownField,
+    CityNotFound,
+    /// Too large body, query string, `maxItems` or `startIndex`
+    LimitExceeded,
+    /// No such HTTP route
+    NotFound,
+    MethodNotAllowed,
+    /// A WebSocket route requested without the WebSocket handshake
+    UpgradeRequired,
+    /// Missing or wrong admin token
+    Unauthorized,
+    /// The dataset is still loading
+    NotReady,
+    /// The time budget ran out before the request could start
+    Timeout,
+    /// The client's rate limit or the server's limit of concurrent searches is reached
+    TooManyRequests,
+    Internal,
+}
+
+/// Serialized as `{"error": {"code": "unknownField", "message": "...", "field": "maxitems"}}`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ApiError {
+    pub code: ErrorCode,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct ErrorResponse<'a> {
+    pub error: &'a ApiError,
+}
+
+impl ApiError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> ApiError {
+        ApiError { code, message: message.into(), field: None }
+    }
+
+    pub fn with_field(mut self, field: impl Into<String>) -> ApiError {
+        self.field = Some(field.into());
+        self
+    }
+
+    pub fn city_not_found(ids: &[usize], field: &str) -> ApiError {
+        let ids_str = ids.iter().map(usize::to_string).collect::<Vec<_>>().join(", ");
+        let message = if ids.len() == 1 {
+            format!("City not found: {}", ids_str)
+        } else {
+            format!("Cities not found: {}", ids_str)
+        };
+        ApiError::new(ErrorCode::CityNotFound, message).with_field(field)
+    }
+
+    /// Classifies a serde error by its message. `command` is the value of the `command` tag,
+    /// if known, to tell an unknown command from other unknown enum variants.
+    ///
+    /// ```
+    /// use backend::library::api_error::{ApiError, ErrorCode};
+    ///
+    /// let unknown_field = ApiError::from_serde_message("unknown field `maxitems`, expected one of `query`, `maxItems`", None);
+    /// assert_eq!(ErrorCode::UnknownField, unknown_field.code);
+    /// assert_eq!(Some("maxitems".into()), unknown_field.field);
+    ///
+    /// let missing_field = ApiError::from_serde_message("missing field `query` at line 1 column 25", None);
+    /// assert_eq!(ErrorCode::BadJson, missing_field.code);
+    /// assert_eq!(Some("query".into()), missing_field.field);
+    ///
+    /// let unknown_command = ApiError::from_serde_message("unknown variant `foo`, expected `searchCity`", Some("foo"));
+    /// assert_eq!(ErrorCode::UnknownCommand, unknown_command.code);
+    /// assert_eq!(Some("command".into()), unknown_command.field);
+    ///
+    /// let other_variant = ApiError::from_serde_message("unknown variant `foo`, expected `bar`", Some("searchCity"));
+    /// assert_eq!(ErrorCode::BadJson, other_variant.code);
+    /// ```
+    pub fn from_serde_message(message: &str, command: Option<&str>) -> ApiError {
+        let backticked = |prefix: &str| message.strip_prefix(prefix)
+            .and_then(|rest| rest.split('`').next())
+            .map(str::to_owned);
+
+        if let Some(field) = backticked("unknown field `") {
+            ApiError::new(ErrorCode::UnknownField, message).with_field(field)
+        } else if let Some(field) = backticked("missing field `") {
+            ApiError::new(ErrorCode::BadJson, message).with_field(field)
+        } else if backticked("unknown variant `").is_some_and(|variant| Some(variant.as_str()) == command) {
+            ApiError::new(ErrorCode::UnknownCommand, message).with_field("command")
+        } else {
+            ApiError::new(ErrorCode::BadJson, message)
+        }
+    }
+
+    pub fn http_status(&self) -> StatusCode {
+        match self.code {
+            ErrorCode::BadJson | ErrorCode::UnknownCommand | ErrorCode::UnknownField => StatusCode::BAD_REQUEST,
+            ErrorCode::CityNotFound | ErrorCode::NotFound => StatusCode::NOT_FOUND,
+            ErrorCode::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            ErrorCode::UpgradeRequired => StatusCode::UPGRADE_REQUIRED,
+            ErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorCode::LimitExceeded => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorCode::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::NotReady | ErrorCode::Timeout => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&ErrorResponse { error: self }).unwrap()
+    }
+}
+
+impl Display for ApiError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.field {
+            Some(field) => write!(f, "{:?} ({}): {}", self.code, field, self.message),
+            None => write!(f, "{:?}: {}", self.code, self.message),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_json() {
+        assert_eq!(
+            r#"{"error":{"code":"cityNotFound","message":"Cities not found: 1, 2","field":"ids"}}"#,
+            ApiError::city_not_found(&[1, 2], "ids").to_json(),
+        );
+        assert_eq!(
+            r#"{"error":{"code":"internal","message":"oops"}}"#,
+            ApiError::new(ErrorCode::Internal, "oops").to_json(),
+        );
+    }
+
+    #[test]
+    fn test_http_status() {
+        assert_eq!(StatusCode::BAD_REQUEST, ApiError::new(ErrorCode::UnknownField, "").http_status());
+        assert_eq!(StatusCode::NOT_FOUND, ApiError::city_not_found(&[1], "id").http_status());
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, ApiError::new(ErrorCode::LimitExceeded, "").http_status());
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, ApiError::new(ErrorCode::Internal, "").http_status());
+    }
+}
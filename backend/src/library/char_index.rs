@@ -0,0 +1,114 @@
+use crate::library::{intern::*, jaro::jaro_winkler_upper_bound};
+use std::collections::HashMap;
+
+/// Inverted index of the characters of interned strings, to find the strings which may be similar enough
+/// to a query before scoring any of them with Jaro-Winkler.
+///
+/// This uses single characters rather than trigrams because only they bound the similarity: Jaro-Winkler
+/// counts matching characters in any order, so "abcd" and "bacd" are 0.92 similar without a trigram in
+/// common. Pruning by `jaro_winkler_upper_bound` thus never drops a string that is similar enough.
+///
+/// ```
+/// use backend::library::{char_index::CharIndex, intern::InternBuilder};
+///
+/// let builder = InternBuilder::new();
+/// let ids = ["paris", "parma", "oslo"].map(|it| builder.intern(it.chars().collect()));
+/// let registry = builder.build();
+/// let index = CharIndex::new(&registry, ids);
+/// let candidates = index.candidates(&registry, &"pari".chars().collect::<Vec<_>>(), 0.85);
+/// assert_eq!([true, false, false], ids.map(|id| candidates[id]));
+/// ```
+pub struct CharIndex {
+    /// Ids of the strings which have the character, and how many times they have it
+    postings: HashMap<char, Vec<(u32, u32)>>,
+    len: usize,
+}
+
+impl CharIndex {
+    /// Indexes the strings of `ids`, each once
+    pub fn new(registry: &InternRegistry, ids: impl IntoIterator<Item = InternId>) -> CharIndex {
+        let mut indexed = vec![false; registry.len()];
+        let mut postings = HashMap::<char, Vec<(u32, u32)>>::new();
+        let mut counts = HashMap::new();
+        for id in ids {
+            if std::mem::replace(&mut indexed[id], true) {
+                continue;
+            }
+            for c in registry.resolve(id).unwrap() {
+                *counts.entry(*c).or_insert(0) += 1;
+            }
+            for (c, count) in counts.drain() {
+                postings.entry(c).or_default().push((id as u32, count));
+            }
+        }
+        CharIndex {
+            postings,
+            len: indexed.iter().filter(|it| **it).count(),
+        }
+    }
+
+    /// Number of indexed strings
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// By id in `registry`, whether the string is indexed and may have a Jaro-Winkler similarity to `query`
+    /// of at least `min_similarity`. An empty query rules out nothing.
+    pub fn candidates(&self, registry: &InternRegistry, query: &[char], min_similarity: f32) -> Vec<bool> {
+        if query.is_empty() {
+            return vec![true; registry.len()];
+        }
+        let mut query_counts = HashMap::<char, u32>::new();
+        for c in query {
+            *query_counts.entry(*c).or_default() += 1;
+        }
+        let mut common = vec![0_u32; registry.len()];
+        for (c, query_count) in &query_counts {
+            for (id, count) in self.postings.get(c).into_iter().flatten() {
+                common[*id as usize] += count.min(query_count);
+            }
+        }
+        common.iter().enumerate()
+            .map(|(id, common)| *common > 0 && {
+                let string = registry.resolve(id).unwrap();
+                let prefix_len = string.iter().zip(query).take(4).take_while(|(a, b)| a == b).count();
+                jaro_winkler_upper_bound(string.len(), query.len(), *common as usize, prefix_len) >= min_similarity
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::library::jaro::jaro_winkler_vec;
+
+    #[test]
+    fn test_candidates_exact() {
+        let strings = ["abcd", "bacd", "dcba", "abcdabcd", "abxy", "xyz", "", "aaaa"];
+        let builder = InternBuilder::new();
+        let ids = strings.map(|it| builder.intern(it.chars().collect()));
+        let registry = builder.build();
+        let index = CharIndex::new(&registry, ids.iter().copied().chain(ids));
+        assert_eq!(strings.len(), index.len());
+
+        for query in ["abcd", "ab", "a", "zyx", "dcbaa"] {
+            let query = query.chars().collect::<Vec<_>>();
+            for min_similarity in [0.0001, 0.3, 0.5, 0.7, 0.8, 0.9, 1.0] {
+                let candidates = index.candidates(&registry, &query, min_similarity);
+                for id in ids {
+                    let similarity = jaro_winkler_vec(registry.resolve(id).unwrap(), &query);
+                    if similarity >= min_similarity {
+                        assert!(candidates[id], "query={:?}, string={:?}", query, registry.resolve(id));
+                    }
+                }
+            }
+        }
+        let candidates = index.candidates(&registry, &['a', 'b', 'c', 'd'], 0.85);
+        assert_eq!([true, true, true, true, false, false, false, false], ids.map(|id| candidates[id]));
+    }
+}
@@ -0,0 +1,193 @@
+use serde_json::Value;
+use std::io::{self, Write};
+
+/// How the CLI prints the items of responses
+#[derive(Debug, Clone, Copy, PartialEq, Default, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// Aligned columns with a header, one table per response
+    #[default]
+    Table,
+    /// One JSON object per item
+    Jsonl,
+    /// Comma-separated values, with a header whenever the columns change
+    Csv,
+}
+
+/// Writes the items of JSON responses: the `items` of searches and of `getCities`, or a `getCity` response
+/// as a single item. In tables and CSV, nested objects become columns such as `city.names`, and arrays are
+/// written as JSON.
+///
+/// ```
+/// use backend::library::cli_output::{OutputFormat, OutputWriter};
+///
+/// let mut out = Vec::new();
+/// let response = serde_json::json!({ "command": "getCity", "id": 7, "city": { "names": ["Paris"], "elevation": null } });
+/// assert_eq!(1, OutputWriter::new(&mut out, OutputFormat::Csv).write_response(&response).unwrap());
+/// assert_eq!("id,city.names,city.elevation\n7,\"[\"\"Paris\"\"]\",\n", String::from_utf8(out).unwrap());
+/// ```
+pub struct OutputWriter<W: Write> {
+    out: W,
+    format: OutputFormat,
+    tables_written: usize,
+    /// Header of the last CSV response
+    csv_columns: Vec<String>,
+}
+
+impl<W: Write> OutputWriter<W> {
+    pub fn new(out: W, format: OutputFormat) -> OutputWriter<W> {
+        OutputWriter { out, format, tables_written: 0, csv_columns: Vec::new() }
+    }
+
+    /// Returns the number of items written. Flushes, so that piped output keeps up with the requests.
+    pub fn write_response(&mut self, response: &Value) -> io::Result<usize> {
+        let items = match response.get("items") {
+            Some(Value::Array(items)) => items.iter().collect(),
+            _ => vec![response],
+        };
+        match self.format {
+            OutputFormat::Jsonl => {
+                for item in &items {
+                    serde_json::to_writer(&mut self.out, item)?;
+                    writeln!(self.out)?;
+                }
+            },
+            OutputFormat::Table if !items.is_empty() => {
+                let (columns, mut rows) = to_rows(&items);
+                if self.tables_written > 0 {
+                    writeln!(self.out)?;
+                }
+                rows.insert(0, columns);
+                write_columns(&mut self.out, &rows)?;
+                self.tables_written += 1;
+            },
+            OutputFormat::Csv if !items.is_empty() => {
+                let (columns, rows) = to_rows(&items);
+                let mut writer = csv::Writer::from_writer(&mut self.out);
+                if columns != self.csv_columns {
+                    writer.write_record(&columns)?;
+                    self.csv_columns = columns;
+                }
+                for row in rows {
+                    writer.write_record(row)?;
+                }
+                writer.flush()?;
+            },
+            OutputFormat::Table | OutputFormat::Csv => {},
+        }
+        self.out.flush()?;
+        Ok(items.len())
+    }
+}
+
+/// Aligns the cells of `rows` in columns; the header, if any, is just the first row
+pub fn write_columns(out: &mut impl Write, rows: &[Vec<String>]) -> io::Result<()> {
+    let len = rows.iter().map(Vec::len).max().unwrap_or_default();
+    let widths = (0..len)
+        .map(|i| rows.iter()
+            .filter_map(|row| row.get(i))
+            .map(|cell| cell.chars().count())
+            .max()
+            .unwrap_or_default())
+        .collect::<Vec<_>>();
+    for row in rows {
+        let line = row.iter().zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ");
+        writeln!(out, "{}", line.trim_end())?;
+    }
+    Ok(())
+}
+
+/// Columns in the order they first appear, and the cells of each item, empty where an item lacks a column
+fn to_rows(items: &[&Value]) -> (Vec<String>, Vec<Vec<String>>) {
+    let mut columns = Vec::<String>::new();
+    let flat_items = items.iter()
+        .map(|item| {
+            let mut cells = Vec::new();
+            flatten("", item, &mut cells);
+            // The `command` tag of a `getCity` response
+            cells.retain(|(column, _)| column != "command");
+            for (column, _) in &cells {
+                if !columns.contains(column) {
+                    columns.push(column.clone());
+                }
+            }
+            cells
+        })
+        .collect::<Vec<_>>();
+    let rows = flat_items.into_iter()
+        .map(|mut cells| columns.iter()
+            .map(|column| cells.iter_mut()
+                .find(|(it, _)| it == column)
+                .map(|(_, cell)| std::mem::take(cell))
+                .unwrap_or_default())
+            .collect())
+        .collect();
+    (columns, rows)
+}
+
+fn flatten(path: &str, value: &Value, cells: &mut Vec<(String, String)>) {
+    match value {
+        Value::Object(fields) => for (key, field) in fields {
+            let field_path = if path.is_empty() { key.clone() } else { format!("{}.{}", path, key) };
+            flatten(&field_path, field, cells);
+        },
+        Value::Null => cells.push((path.to_owned(), String::new())),
+        Value::String(text) => cells.push((path.to_owned(), text.clone())),
+        _ => cells.push((path.to_owned(), value.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn write(format: OutputFormat, responses: &[Value]) -> String {
+        let mut out = Vec::new();
+        let mut writer = OutputWriter::new(&mut out, format);
+        for response in responses {
+            writer.write_response(response).unwrap();
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_table() {
+        let search = json!({ "command": "searchCity", "items": [
+            { "id": 1, "name": "Paris", "adminUnit": "Île-de-France", "city": { "names": ["Paris"] } },
+            { "id": 22, "name": "Paris", "adminUnit": null },
+        ], "elapsedMs": 3 });
+        let empty = json!({ "command": "searchCity", "items": [], "elapsedMs": 1 });
+        let expected = "\
+id  name   adminUnit      city.names
+1   Paris  Île-de-France  [\"Paris\"]
+22  Paris
+
+id
+1
+";
+        assert_eq!(expected, write(OutputFormat::Table, &[search, empty, json!({ "items": [{ "id": 1 }] })]));
+    }
+
+    #[test]
+    fn test_csv_header() {
+        let climate = json!({ "items": [{ "id": 1, "similarityPercent": 99.5 }] });
+        let city = json!({ "command": "getCity", "id": 2, "city": { "country": "FR, \"France\"" } });
+        let expected = "\
+id,similarityPercent
+1,99.5
+1,99.5
+id,city.country
+2,\"FR, \"\"France\"\"\"
+";
+        assert_eq!(expected, write(OutputFormat::Csv, &[climate.clone(), climate, city]));
+    }
+
+    #[test]
+    fn test_jsonl() {
+        let cities = json!({ "command": "getCities", "items": [{ "id": 1, "city": { "names": ["A"] } }, { "id": 2 }] });
+        assert_eq!("{\"id\":1,\"city\":{\"names\":[\"A\"]}}\n{\"id\":2}\n", write(OutputFormat::Jsonl, &[cities]));
+    }
+}
@@ -1,11 +1,24 @@
-use crate::library::{api::*, earth::*, minmax::*};
+use crate::library::{api::*, deadline::Deadline, earth::*, minmax::*, result_cache::ResultCache};
 use common::{city::City, util::round_0_1_and_assert_finite};
 use rayon::prelude::*;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 
 pub struct ClimateSearchData {
     /// Order: same as in the Cities list
     items: Vec<ClimateSearchItem>,
+    /// By the id of the searched city
+    rankings: ResultCache<usize, ClimateRanking>,
+}
+
+/// The first results of a search, best first
+#[derive(Debug)]
+struct ClimateRanking {
+    /// Id and climate difference
+    items: Vec<(u32, f32)>,
+    max_diff: f32,
+    /// All candidates were filtered, so there are no more results
+    complete: bool,
 }
 
 #[derive(Debug)]
@@ -26,7 +39,8 @@ struct ClimateMinMax {
     ws: (f32, f32),
 }
 
-pub fn make_climate_search_data(cities: &Vec<City>) -> ClimateSearchData {
+/// Up to `cache_bytes` of rankings are cached, see `ResultCache`
+pub fn make_climate_search_data(cities: &Vec<City>, cache_bytes: usize) -> ClimateSearchData {
     let start = std::time::Instant::now();
 
     let total_minmax = cities.par_iter()
@@ -60,9 +74,9 @@ pub fn make_climate_search_data(cities: &Vec<City>) -> ClimateSearchData {
         })
         .collect();
 
-    eprintln!("Built climate search items in {} ms", start.elapsed().as_millis());
+    tracing::info!(elapsed_ms = start.elapsed().as_millis() as u64, "Built climate search items");
 
-    ClimateSearchData { items }
+    ClimateSearchData { items, rankings: ResultCache::new("climate", cache_bytes) }
 }
 
 fn get_climate_min_max(city: &City) -> ClimateMinMax {
@@ -85,88 +99,143 @@ fn get_climate_min_max(city: &City) -> ClimateMinMax {
 #[derive(Debug)]
 struct ClimateScoredItem<'a> {
     id: usize,
-    city: &'a City,
     cartesian_xyz: &'a [f64; 3],
     diff: f32,
 }
 
-pub fn search_climate<'a>(cities: &'a Vec<City>, data: &'a ClimateSearchData, city_id: usize, start_index: usize, max_items: usize) -> ClimateSearchResponse<'a> {
+/// When `deadline` expires, scoring and selection stop, and the response is `truncated`.
+/// Rankings are cached one page ahead, so the next page of the same search is not scored again.
+pub fn search_climate<'a>(cities: &'a Vec<City>, data: &'a ClimateSearchData, city_id: usize, start_index: usize, max_items: usize, deadline: &Deadline) -> ClimateSearchResponse<'a> {
     let started = std::time::Instant::now();
-    let query_maybe = &data.items.get(city_id);
-    if query_maybe.is_none() {
+    let Some(query) = data.items.get(city_id) else {
         return ClimateSearchResponse {
             items: vec![],
             elapsed_ms: started.elapsed().as_millis() as u32,
+            truncated: false,
         };
-    }
-    let query = query_maybe.unwrap();
+    };
 
+    let needed = start_index.saturating_add(max_items);
+    let cached = data.rankings.get_if(&city_id, |it| it.complete || it.items.len() >= needed);
+    let ranking = match cached {
+        Some(ranking) => ranking,
+        None => {
+            let (ranking, truncated) = rank_climate(data, query, needed.saturating_add(max_items), deadline);
+            if truncated {
+                return make_response(cities, query, &ranking, start_index, max_items, started, true);
+            }
+            let bytes = ranking.items.capacity() * size_of::<(u32, f32)>();
+            data.rankings.insert(city_id, ranking, bytes)
+        },
+    };
+    make_response(cities, query, &ranking, start_index, max_items, started, false)
+}
+
+/// Finds the `len` best cities which are at least 200 km apart from each other, and whether `deadline`
+/// made it skip any
+fn rank_climate(data: &ClimateSearchData, query: &ClimateSearchItem, len: usize, deadline: &Deadline) -> (ClimateRanking, bool) {
     // Chord length is much faster to calculate, using it for filtering
     let min_chord_length = arc_length_to_chord_length(200.0);
     let min_chord_length_sq = min_chord_length * min_chord_length;
 
-    let (scored_items, max_diff) = score_and_pre_filter_items(cities,data, query);
+    let (scored_items, max_diff, mut truncated) = score_and_pre_filter_items(data, query, deadline);
 
     let mut filtered_items = Vec::<ClimateScoredItem>::new();
 
     filtered_items.push(ClimateScoredItem {
         id: query.id,
-        city: &cities[query.id],
         cartesian_xyz: &query.cartesian_xyz,
         diff: 0.0,
     });
 
+    let mut complete = true;
     for item in scored_items {
+        if deadline.is_expired() {
+            truncated = true;
+            break;
+        }
         // It's faster than spatial index (e.g. rstar)
         if filtered_items.iter().all(|existing_res_it|
             get_cartesian_distance_km_squared(&item.cartesian_xyz, &existing_res_it.cartesian_xyz) >= min_chord_length_sq
         ) {
             filtered_items.push(item);
-            if filtered_items.len() >= start_index + max_items {
+            if filtered_items.len() >= len {
+                complete = false;
                 break;
             }
         }
     }
 
-    eprintln!("Selected {} results", filtered_items.len());
+    tracing::debug!(results = filtered_items.len(), "Selected climate search results");
 
-    let result_items = filtered_items.into_iter()
+    let ranking = ClimateRanking {
+        items: filtered_items.into_iter().map(|item| (item.id as u32, item.diff)).collect(),
+        max_diff,
+        complete,
+    };
+    (ranking, truncated)
+}
+
+fn make_response<'a>(
+    cities: &'a [City],
+    query: &ClimateSearchItem,
+    ranking: &ClimateRanking,
+    start_index: usize,
+    max_items: usize,
+    started: std::time::Instant,
+    truncated: bool,
+) -> ClimateSearchResponse<'a> {
+    let query_city = &cities[query.id];
+    let items = ranking.items.iter()
         .skip(start_index)
-        .map(|item| ClimateSearchResponseItem {
-            id: item.id,
-            city: item.city,
-            distance_km: round_0_1_and_assert_finite(
-                get_arc_distance_km(
-                    item.city.latitude,
-                    item.city.longitude,
-                    cities[query.id].latitude,
-                    cities[query.id].longitude,
-                )
-            ),
-            similarity_percent: 100.0 * (1.0 - item.diff / max_diff),
-        })
         .take(max_items)
+        .map(|&(id, diff)| {
+            let city = &cities[id as usize];
+            ClimateSearchResponseItem {
+                id: id as usize,
+                city: ProjectedCity::Full(city),
+                distance_km: round_0_1_and_assert_finite(
+                    get_arc_distance_km(
+                        city.latitude,
+                        city.longitude,
+                        query_city.latitude,
+                        query_city.longitude,
+                    )
+                ),
+                similarity_percent: 100.0 * (1.0 - diff / ranking.max_diff),
+            }
+        })
         .collect::<Vec<_>>();
 
     ClimateSearchResponse {
-        items: result_items,
+        items,
         elapsed_ms: started.elapsed().as_millis() as u32,
+        truncated,
     }
 }
 
-fn score_and_pre_filter_items<'a>(cities: &'a Vec<City>, data: &'a ClimateSearchData, query: &'a ClimateSearchItem) -> (Vec<ClimateScoredItem<'a>>, f32) {
+/// Also whether `deadline` made it skip any item
+fn score_and_pre_filter_items<'a>(data: &'a ClimateSearchData, query: &'a ClimateSearchItem, deadline: &Deadline) -> (Vec<ClimateScoredItem<'a>>, f32, bool) {
+    let truncated = AtomicBool::new(false);
     let scored_items = data.items.par_iter().enumerate()
+        .filter(|_| {
+            let expired = deadline.is_expired();
+            if expired {
+                truncated.store(true, Ordering::Relaxed);
+            }
+            !expired
+        })
         .map(|(index, item)| ClimateScoredItem {
             id: index,
-            city: &cities[index],
             cartesian_xyz: &item.cartesian_xyz,
             diff: get_climate_diff(item, query),
         })
         .collect::<Vec<_>>();
 
+    // Nothing scored if the deadline expired right away
     let max_diff = scored_items.par_iter()
         .max_by(|a, b| a.diff.total_cmp(&b.diff))
-        .unwrap().diff;
+        .map_or(1.0, |it| it.diff);
 
     let mut pre_filtered = scored_items.into_par_iter()
         .filter(|item| item.diff < max_diff / 2.0)
@@ -174,7 +243,7 @@ fn score_and_pre_filter_items<'a>(cities: &'a Vec<City>, data: &'a ClimateSearch
     
     pre_filtered.par_sort_by(|a, b| a.diff.total_cmp(&b.diff));
 
-    (pre_filtered, max_diff)
+    (pre_filtered, max_diff, truncated.into_inner())
 }
 
 fn get_climate_diff(item: &ClimateSearchItem, query: &ClimateSearchItem) -> f32 {
@@ -0,0 +1,124 @@
+use std::io::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Identity,
+    Gzip,
+    Brotli,
+    Zstd,
+}
+
+/// When a client accepts several encodings with the same weight, the first one here wins
+const PREFERENCE: [Encoding; 3] = [Encoding::Brotli, Encoding::Zstd, Encoding::Gzip];
+
+impl Encoding {
+    /// Value for `Content-Encoding`, None for identity
+    pub fn header_value(self) -> Option<&'static str> {
+        match self {
+            Encoding::Identity => None,
+            Encoding::Gzip => Some("gzip"),
+            Encoding::Brotli => Some("br"),
+            Encoding::Zstd => Some("zstd"),
+        }
+    }
+
+    /// Picks the best supported encoding from an `Accept-Encoding` value, honoring `q` weights and `*`.
+    ///
+    /// ```
+    /// use backend::library::compression::Encoding;
+    ///
+    /// assert_eq!(Encoding::Brotli, Encoding::negotiate(Some("gzip, deflate, br, zstd")));
+    /// assert_eq!(Encoding::Gzip, Encoding::negotiate(Some("gzip;q=1.0, br;q=0.5")));
+    /// assert_eq!(Encoding::Identity, Encoding::negotiate(Some("deflate")));
+    /// assert_eq!(Encoding::Identity, Encoding::negotiate(None));
+    /// ```
+    pub fn negotiate(accept_encoding: Option<&str>) -> Encoding {
+        let Some(accept_encoding) = accept_encoding else {
+            return Encoding::Identity;
+        };
+
+        let weights = accept_encoding.split(',')
+            .filter_map(|item| {
+                let mut parts = item.split(';').map(str::trim);
+                let name = parts.next().filter(|it| !it.is_empty())?.to_ascii_lowercase();
+                let q = parts
+                    .find_map(|param| param.strip_prefix("q="))
+                    .map_or(Some(1.0), |q| q.parse::<f32>().ok())?;
+                Some((name, q))
+            })
+            .collect::<Vec<_>>();
+        let weight_of = |encoding: Encoding| {
+            let name = encoding.header_value().unwrap();
+            weights.iter().find(|(it, _)| it == name)
+                .or_else(|| weights.iter().find(|(it, _)| it == "*"))
+                .map_or(0.0, |(_, q)| *q)
+        };
+
+        let mut best = (Encoding::Identity, 0.0);
+        for encoding in PREFERENCE {
+            let weight = weight_of(encoding);
+            if weight > best.1 {
+                best = (encoding, weight);
+            }
+        }
+        best.0
+    }
+
+    /// Levels trade some ratio for speed, as responses are compressed on every request
+    pub fn compress(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Encoding::Identity => data.to_vec(),
+            Encoding::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::new(5));
+                encoder.write_all(data).unwrap();
+                encoder.finish().unwrap()
+            },
+            Encoding::Brotli => {
+                let mut out = Vec::new();
+                {
+                    let mut encoder = brotli::CompressorWriter::new(&mut out, 4096, 5, 22);
+                    encoder.write_all(data).unwrap();
+                }
+                out
+            },
+            Encoding::Zstd => zstd::bulk::compress(data, 3).unwrap(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(Encoding::Zstd, Encoding::negotiate(Some("gzip, zstd")));
+        assert_eq!(Encoding::Brotli, Encoding::negotiate(Some("*")));
+        assert_eq!(Encoding::Zstd, Encoding::negotiate(Some("br;q=0, *;q=0.5")));
+        assert_eq!(Encoding::Identity, Encoding::negotiate(Some("gzip;q=0")));
+        assert_eq!(Encoding::Identity, Encoding::negotiate(Some("gzip;q=x")));
+        assert_eq!(Encoding::Gzip, Encoding::negotiate(Some(" GZIP ; q=0.8 ")));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let data = "{\"items\":[".to_owned() + &"{\"name\":\"Paris\"},".repeat(100) + "]}";
+        let data = data.as_bytes();
+
+        let gzip = Encoding::Gzip.compress(data);
+        let mut gunzipped = Vec::new();
+        flate2::read::GzDecoder::new(gzip.as_slice()).read_to_end(&mut gunzipped).unwrap();
+        assert_eq!(data, gunzipped);
+
+        let br = Encoding::Brotli.compress(data);
+        let mut unbrotlied = Vec::new();
+        brotli::Decompressor::new(br.as_slice(), 4096).read_to_end(&mut unbrotlied).unwrap();
+        assert_eq!(data, unbrotlied);
+
+        let zstd = Encoding::Zstd.compress(data);
+        assert_eq!(data, zstd::decode_all(zstd.as_slice()).unwrap());
+
+        assert!(br.len() < data.len() / 10);
+    }
+}
@@ -0,0 +1,99 @@
+use std::{sync::{atomic::{AtomicBool, Ordering}, Arc}, time::{Duration, Instant}};
+
+/// Time budget of a request, which can also be cancelled explicitly, e.g. when the client disconnects.
+/// Clones share the cancellation, so a search checks the same flag the server sets.
+///
+/// ```
+/// use backend::library::deadline::Deadline;
+/// use std::time::Duration;
+///
+/// let deadline = Deadline::after(Duration::from_secs(60));
+/// assert!(!deadline.is_expired());
+/// deadline.clone().cancel();
+/// assert!(deadline.is_expired());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Deadline {
+    at: Option<Instant>,
+    expired: Arc<AtomicBool>,
+}
+
+impl Deadline {
+    /// Never expires unless cancelled
+    pub fn none() -> Deadline {
+        Deadline::default()
+    }
+
+    pub fn after(budget: Duration) -> Deadline {
+        Deadline {
+            at: Some(Instant::now() + budget),
+            expired: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.expired.store(true, Ordering::Relaxed);
+    }
+
+    /// Cheap enough to call per scanned item
+    pub fn is_expired(&self) -> bool {
+        if self.expired.load(Ordering::Relaxed) {
+            return true;
+        }
+        if self.at.is_some_and(|at| Instant::now() >= at) {
+            self.cancel();
+            return true;
+        }
+        false
+    }
+
+    /// None if there's no time limit; zero if expired
+    pub fn remaining(&self) -> Option<Duration> {
+        if self.is_expired() {
+            return Some(Duration::ZERO);
+        }
+        self.at.map(|at| at.saturating_duration_since(Instant::now()))
+    }
+
+    /// Cancels the deadline when dropped, unless disarmed.
+    /// Held by a future whose drop means nobody waits for the result.
+    pub fn cancel_on_drop(&self) -> CancelOnDrop {
+        CancelOnDrop(Some(self.clone()))
+    }
+}
+
+pub struct CancelOnDrop(Option<Deadline>);
+
+impl CancelOnDrop {
+    /// The result was delivered, so there's nothing to cancel
+    pub fn disarm(mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        if let Some(deadline) = &self.0 {
+            deadline.cancel();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expiry() {
+        assert!(!Deadline::none().is_expired());
+        assert!(Deadline::after(Duration::ZERO).is_expired());
+        assert_eq!(None, Deadline::none().remaining());
+        assert_eq!(Some(Duration::ZERO), Deadline::after(Duration::ZERO).remaining());
+
+        let deadline = Deadline::after(Duration::from_secs(60));
+        deadline.cancel_on_drop().disarm();
+        assert!(!deadline.is_expired());
+        drop(deadline.cancel_on_drop());
+        assert!(deadline.is_expired());
+    }
+}
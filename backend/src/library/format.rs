@@ -0,0 +1,190 @@
+use crate::library::api_error::*;
+use serde::{de::DeserializeOwned, Serialize};
+
+/// How requests and responses are serialized over HTTP: the same serde types either way
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Format {
+    #[default]
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+/// Media types by format; the first one is sent in `Content-Type`
+const MEDIA_TYPES: [(Format, &[&str]); 3] = [
+    (Format::Json, &["application/json"]),
+    (Format::MessagePack, &["application/msgpack", "application/x-msgpack", "application/vnd.msgpack"]),
+    (Format::Cbor, &["application/cbor"]),
+];
+
+impl Format {
+    pub fn content_type(self) -> &'static str {
+        MEDIA_TYPES.iter().find(|(format, _)| *format == self).unwrap().1[0]
+    }
+
+    /// Format of a request body. Anything but MessagePack or CBOR is read as JSON, as clients
+    /// such as `curl -d` send JSON with other content types.
+    ///
+    /// ```
+    /// use backend::library::format::Format;
+    ///
+    /// assert_eq!(Format::MessagePack, Format::from_content_type(Some("application/x-msgpack")));
+    /// assert_eq!(Format::Cbor, Format::from_content_type(Some("Application/CBOR; charset=binary")));
+    /// assert_eq!(Format::Json, Format::from_content_type(Some("application/x-www-form-urlencoded")));
+    /// assert_eq!(Format::Json, Format::from_content_type(None));
+    /// ```
+    pub fn from_content_type(content_type: Option<&str>) -> Format {
+        content_type
+            .map(|it| it.split(';').next().unwrap().trim().to_ascii_lowercase())
+            .and_then(|media_type| find_format(&media_type))
+            .unwrap_or_default()
+    }
+
+    /// Picks the response format from an `Accept` value, honoring `q` weights. A more specific media range
+    /// wins over a wildcard of the same weight; among equals, the first one listed. Falls back to JSON.
+    ///
+    /// ```
+    /// use backend::library::format::Format;
+    ///
+    /// assert_eq!(Format::MessagePack, Format::negotiate(Some("application/msgpack, application/json;q=0.5")));
+    /// assert_eq!(Format::Cbor, Format::negotiate(Some("*/*, application/cbor")));
+    /// assert_eq!(Format::Json, Format::negotiate(Some("application/cbor;q=0.1, application/*")));
+    /// assert_eq!(Format::Json, Format::negotiate(Some("text/html")));
+    /// assert_eq!(Format::Json, Format::negotiate(None));
+    /// ```
+    pub fn negotiate(accept: Option<&str>) -> Format {
+        let Some(accept) = accept else {
+            return Format::Json;
+        };
+
+        let mut best = (Format::Json, 0.0, 0);
+        for item in accept.split(',') {
+            let mut parts = item.split(';').map(str::trim);
+            let media_range = parts.next().unwrap_or_default().to_ascii_lowercase();
+            let Some(q) = parts
+                .find_map(|param| param.strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.parse::<f32>().ok()) else {
+                continue;
+            };
+            let (format, specificity) = match media_range.as_str() {
+                "*/*" => (Format::Json, 1),
+                "application/*" => (Format::Json, 2),
+                media_type => match find_format(media_type) {
+                    Some(format) => (format, 3),
+                    None => continue,
+                },
+            };
+            if q > best.1 || (q == best.1 && q > 0.0 && specificity > best.2) {
+                best = (format, q, specificity);
+            }
+        }
+        best.0
+    }
+
+    pub fn serialize<T: Serialize>(self, value: &T) -> Vec<u8> {
+        match self {
+            Format::Json => serde_json::to_vec(value).unwrap(),
+            // As maps with field names, like JSON objects
+            Format::MessagePack => rmp_serde::to_vec_named(value).unwrap(),
+            Format::Cbor => {
+                let mut out = Vec::new();
+                ciborium::into_writer(value, &mut out).unwrap();
+                out
+            },
+        }
+    }
+
+    pub fn serialize_error(self, error: &ApiError) -> Vec<u8> {
+        self.serialize(&ErrorResponse { error })
+    }
+
+    /// Malformed input fails with `badJson`, whatever the format
+    pub fn deserialize<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T, ApiError> {
+        let result = match self {
+            Format::Json => serde_json::from_slice(bytes).map_err(|e| e.to_string()),
+            Format::MessagePack => rmp_serde::from_slice(bytes).map_err(|e| e.to_string()),
+            Format::Cbor => ciborium::from_reader(bytes).map_err(|e| e.to_string()),
+        };
+        result.map_err(|message| ApiError::new(ErrorCode::BadJson, message))
+    }
+
+    /// Makes an array of values which are already serialized, so that they can be serialized in parallel
+    ///
+    /// ```
+    /// use backend::library::format::Format;
+    ///
+    /// for format in [Format::Json, Format::MessagePack, Format::Cbor] {
+    ///     let items = vec![format.serialize(&1), format.serialize(&"two")];
+    ///     let array = format.join_array(items);
+    ///     assert_eq!(format.serialize(&(1, "two")), array);
+    /// }
+    /// ```
+    pub fn join_array(self, items: Vec<Vec<u8>>) -> Vec<u8> {
+        let len = items.len();
+        let mut out = match self {
+            Format::Json => {
+                let mut out = vec![b'['];
+                out.extend(items.join(&b','));
+                out.push(b']');
+                return out;
+            },
+            // https://github.com/msgpack/msgpack/blob/master/spec.md#array-format-family
+            Format::MessagePack => match len {
+                0..16 => vec![0x90 | len as u8],
+                16..=0xffff => [&[0xdc][..], &(len as u16).to_be_bytes()].concat(),
+                _ => [&[0xdd][..], &(len as u32).to_be_bytes()].concat(),
+            },
+            // https://www.rfc-editor.org/rfc/rfc8949#section-3.1, major type 4
+            Format::Cbor => match len {
+                0..24 => vec![0x80 | len as u8],
+                24..=0xff => vec![0x98, len as u8],
+                0x100..=0xffff => [&[0x99][..], &(len as u16).to_be_bytes()].concat(),
+                _ => [&[0x9a][..], &(len as u32).to_be_bytes()].concat(),
+            },
+        };
+        out.extend(items.concat());
+        out
+    }
+}
+
+fn find_format(media_type: &str) -> Option<Format> {
+    MEDIA_TYPES.iter()
+        .find(|(_, media_types)| media_types.contains(&media_type))
+        .map(|(format, _)| *format)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::library::api::*;
+
+    #[test]
+    fn test_request_round_trip() {
+        let json = serde_json::json!({ "command": "searchCity", "query": "paris", "maxItems": 3, "fields": ["names[0]"] });
+        for format in [Format::MessagePack, Format::Cbor] {
+            let bytes = format.serialize(&json);
+            assert_eq!(json, format.deserialize::<serde_json::Value>(&bytes).unwrap());
+            assert!(matches!(
+                format.deserialize::<CityRequest>(&bytes),
+                Ok(CityRequest::SearchCity(CitySearchRequest { max_items: Some(3), .. }))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_large_array() {
+        for len in [15, 16, 23, 24, 255, 256, 70_000] {
+            for format in [Format::Json, Format::MessagePack, Format::Cbor] {
+                let items = (0..len).map(|it| format.serialize(&it)).collect();
+                let array = format.deserialize::<Vec<u32>>(&format.join_array(items)).unwrap();
+                assert_eq!((0..len).collect::<Vec<_>>(), array);
+            }
+        }
+    }
+
+    #[test]
+    fn test_malformed() {
+        let err = Format::MessagePack.deserialize::<serde_json::Value>(&[0xc1]).unwrap_err();
+        assert_eq!(ErrorCode::BadJson, err.code);
+    }
+}
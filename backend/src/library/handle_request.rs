@@ -1,117 +1,568 @@
-use crate::library::{api::*, climate_search::*, search::*};
+use crate::library::{api::*, api_error::*, climate_search::*, deadline::Deadline, format::Format, metrics, projection::*, result_cache, search::*};
 
-use common::{city::City, city_csv::read_cities, util::eprintln_memory_usage};
-use once_cell::sync::Lazy;
+use arc_swap::ArcSwapOption;
+use common::{city::City, city_csv::{read_dataset, Dataset}, util::log_memory_usage};
+use rayon::prelude::*;
+use std::{sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex}, time::{Duration, Instant}};
 
+/// The stream scans the cities in this many parts, for partial results in between
+const STREAM_CHUNKS: usize = 8;
+/// Partial results are sent only when the search is slower than this, and at most this often
+const STREAM_PARTIAL_INTERVAL: Duration = Duration::from_millis(50);
 
-pub fn handle_request(req_str: String, is_cli: bool) -> Result<String, String> {
-    parse_request(&req_str, is_cli)
-        .and_then(|request| {
-            let response = handle_request_impl(request);
-            Ok(to_string_response(response, is_cli))
+/// Settings which are not part of a request but affect its handling
+#[derive(Debug, Clone)]
+pub struct RequestOptions {
+    /// `maxItems` default for `searchCity`
+    pub search_page_size: usize,
+    /// `maxItems` default for `searchClimate`
+    pub climate_page_size: usize,
+    /// Shared by all sub-requests of a batch
+    pub deadline: Deadline,
+    /// Larger `maxItems` fails with `limitExceeded`; None means no limit
+    pub max_items_limit: Option<usize>,
+    /// Larger `startIndex` fails with `limitExceeded`; None means no limit
+    pub start_index_limit: Option<usize>,
+    /// Longer batches and `getCities` ids fail with `limitExceeded`; None means no limit
+    pub max_batch_len: Option<usize>,
+    /// Serialization of responses
+    pub response_format: Format,
+}
+
+impl Default for RequestOptions {
+    fn default() -> Self {
+        RequestOptions {
+            search_page_size: SEARCH_DEFAULT_MAX_ITEMS,
+            climate_page_size: CLIMATE_DEFAULT_MAX_ITEMS,
+            deadline: Deadline::none(),
+            max_items_limit: None,
+            start_index_limit: None,
+            max_batch_len: None,
+            response_format: Format::Json,
+        }
+    }
+}
+
+pub fn handle_request(req_str: String, is_cli: bool) -> Result<String, ApiError> {
+    let data = data_snapshot()?;
+    handle_request_with_options(&data, req_str.as_bytes(), Format::Json, is_cli, &RequestOptions::default())
+        .map(|response| String::from_utf8(response).unwrap())
+}
+
+/// Handles the request, serialized in `format`, against `data`, which stays the same for all sub-requests
+/// of a batch even if the dataset is reloaded meanwhile. With `is_cli`, instead of JSON the request may be
+/// in the simple syntax of the CLI: a city name, a city id, or `city <id> [<id>...]`.
+pub fn handle_request_with_options(data: &CachedData, req: &[u8], format: Format, is_cli: bool, options: &RequestOptions) -> Result<Vec<u8>, ApiError> {
+    let parsed = match format {
+        Format::Json => {
+            let req_str = std::str::from_utf8(req).map_err(|e| ApiError::new(ErrorCode::BadJson, e.to_string()))?;
+            if req_str.trim_start().starts_with('[') {
+                return handle_batch_request(data, format.deserialize(req)?, options);
+            }
+            parse_request(req_str, is_cli)
+        },
+        Format::MessagePack | Format::Cbor => match format.deserialize(req)? {
+            serde_json::Value::Array(sub_requests) => return handle_batch_request(data, sub_requests, options),
+            value => request_from_value(value),
+        },
+    };
+    match parsed {
+        Ok(request) => handle_city_request(data, request, options),
+        Err(err) => {
+            metrics::observe_request(metrics::INVALID_COMMAND, Some(&err));
+            Err(err)
+        },
+    }
+}
+
+/// Sub-requests run in parallel, and a failed one doesn't fail the others. Since the batch is one request
+/// for the limits of the server, its length and the `maxItems` of all its sub-requests together are limited.
+fn handle_batch_request(data: &CachedData, sub_requests: Vec<serde_json::Value>, options: &RequestOptions) -> Result<Vec<u8>, ApiError> {
+    check_len(sub_requests.len(), options.max_batch_len, "The batch")?;
+    let sub_requests = sub_requests.into_iter()
+        .map(|sub_request| request_from_value(sub_request)
+            .inspect_err(|err| metrics::observe_request(metrics::INVALID_COMMAND, Some(err))))
+        .collect::<Vec<_>>();
+    check_batch_items(sub_requests.iter().flatten(), options)?;
+
+    // Rayon threads don't inherit the current span, so sub-request logs are tied to the request explicitly
+    let parent_span = tracing::Span::current();
+    let sub_responses = sub_requests.into_par_iter()
+        .map(|sub_request| {
+            let _entered = parent_span.enter();
+            sub_request
+                .and_then(|request| handle_city_request(data, request, options))
+                .unwrap_or_else(|error| options.response_format.serialize_error(&error))
         })
+        .collect::<Vec<_>>();
+    Ok(options.response_format.join_array(sub_responses))
+}
+
+/// Same as `handle_request_with_options` but for an already parsed request
+pub fn handle_city_request(data: &CachedData, request: CityRequest, options: &RequestOptions) -> Result<Vec<u8>, ApiError> {
+    let command = request.command();
+    let span = command_span(data, command);
+    let _entered = span.enter();
+    match &request {
+        CityRequest::SearchCity(req) => { span.record("query", req.query.as_str()); },
+        CityRequest::SearchClimate(req) => { span.record("city_id", req.city_id); },
+        CityRequest::GetCity(req) => { span.record("city_id", req.id); },
+        CityRequest::GetCities(_) => {},
+    }
+
+    let started = Instant::now();
+    let response = handle_request_impl(data, request, options);
+    let handled = Instant::now();
+    let result = response.map(|response| options.response_format.serialize(&response));
+    observe_command(command, started, handled, &result);
+    result
+}
+
+/// Parses a message of the `GET /cities/stream` WebSocket, which must be a `searchCity` command
+pub fn parse_search_stream_request(req_str: &str) -> Result<CitySearchRequest, ApiError> {
+    let request = parse_request(req_str, false).and_then(|request| match request {
+        CityRequest::SearchCity(req) => Ok(req),
+        other => Err(
+            ApiError::new(ErrorCode::UnknownCommand, format!("Only searchCity can be streamed, not {}", other.command())).with_field("command")
+        ),
+    });
+    request.inspect_err(|err| metrics::observe_request(metrics::INVALID_COMMAND, Some(err)))
+}
+
+/// Searches like `handle_city_request`, but also passes partial results to `send_partial`
+/// if the search takes long enough. Each message is a `CitySearchStreamResponse`.
+pub fn handle_search_stream_request(
+    data: &CachedData,
+    req: CitySearchRequest,
+    options: &RequestOptions,
+    mut send_partial: impl FnMut(String),
+) -> Result<String, ApiError> {
+    let command = "searchCity";
+    let span = command_span(data, command);
+    let _entered = span.enter();
+    span.record("query", req.query.as_str());
+
+    let to_message = |response, partial| serde_json::to_string(
+        &CitySearchStreamResponse { query: &req.query, partial, response }
+    ).unwrap();
+    let started = Instant::now();
+    let mut last_sent = started;
+    let response = search_city(data, &req, options, STREAM_CHUNKS, |response| {
+        if last_sent.elapsed() >= STREAM_PARTIAL_INTERVAL {
+            send_partial(to_message(response, true));
+            last_sent = Instant::now();
+        }
+    });
+    let handled = Instant::now();
+    let result = response.map(|response| to_message(response, false));
+    observe_command(command, started, handled, &result);
+    result
+}
+
+fn command_span(data: &CachedData, command: &str) -> tracing::Span {
+    tracing::info_span!(
+        "command",
+        command,
+        query = tracing::field::Empty,
+        city_id = tracing::field::Empty,
+        dataset_version = %data.version,
+    )
 }
 
-fn parse_request(req_str: &str, is_cli: bool) -> Result<CityRequest, String> {
+fn observe_command<T>(command: &str, started: Instant, handled: Instant, result: &Result<T, ApiError>) {
+    tracing::debug!(
+        handle_ms = (handled - started).as_micros() as f64 / 1000.0,
+        serialize_ms = handled.elapsed().as_micros() as f64 / 1000.0,
+        error = result.as_ref().err().map(tracing::field::display),
+        "Handled command",
+    );
+    metrics::observe_request(command, result.as_ref().err());
+}
+
+/// The currently served dataset. A request should take it once and use it till the end,
+/// so that it's answered from a single dataset version.
+pub fn data_snapshot() -> Result<Arc<CachedData>, ApiError> {
+    CACHED_DATA.load_full().ok_or_else(|| ApiError::new(ErrorCode::NotReady, "Data is not loaded yet"))
+}
+
+/// Reads the dataset and builds the search indexes, unless already done.
+/// Binaries call it on startup so that the first request doesn't pay for it.
+pub fn init_data() -> Result<(), String> {
+    if CACHED_DATA.load().is_some() {
+        return Ok(());
+    }
+    reload_data().map(|_| ())
+}
+
+pub struct ReloadResult {
+    pub version: String,
+    /// False if the shards didn't change, in which case the indexes are not rebuilt
+    pub changed: bool,
+}
+
+/// Re-reads the shards and, if they changed, builds new indexes and swaps them in.
+/// Requests which already took a snapshot finish against the old one; the old data is freed
+/// when the last of them completes. On error the current data is kept.
+pub fn reload_data() -> Result<ReloadResult, String> {
+    let _reloading = RELOAD_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let started = Instant::now();
+
+    let Dataset { cities, version } = read_dataset()?;
+    if CACHED_DATA.load().as_ref().is_some_and(|current| current.version == version) {
+        return Ok(ReloadResult { version, changed: false });
+    }
+
+    let cache_bytes = RESULT_CACHE_BYTES.load(Ordering::Relaxed);
+    let search_data = make_search_data(&cities, cache_bytes);
+    let climate_search_data = make_climate_search_data(&cities, cache_bytes);
+    metrics::set_dataset(cities.len(), started.elapsed());
+    let data = CachedData { cities, version: version.clone(), search_data, climate_search_data };
+    CACHED_DATA.store(Some(Arc::new(data)));
+    log_memory_usage();
+    Ok(ReloadResult { version, changed: true })
+}
+
+fn parse_request(req_str: &str, is_cli: bool) -> Result<CityRequest, ApiError> {
     let simple_cmd_allowed = is_cli && !req_str.contains("{") && !req_str.contains("}");
-    let req_json_res = serde_json::from_str::<CityRequest>(req_str).map_err(|e| e.to_string());
+    let req_json_res = serde_json::from_str::<serde_json::Value>(req_str)
+        .map_err(|e| ApiError::new(ErrorCode::BadJson, e.to_string()))
+        .and_then(request_from_value);
     if req_json_res.is_ok() || !simple_cmd_allowed {
         return req_json_res
     }
 
     let simple_req =
-        if let Ok(id) = req_str.parse() {
+        if let Some(ids) = parse_simple_city_ids(req_str) {
+            if ids.len() == 1 {
+                CityRequest::GetCity(CityGetRequest { id: ids[0] })
+            } else {
+                CityRequest::GetCities(CitiesGetRequest { ids })
+            }
+        } else if let Ok(id) = req_str.parse() {
             CityRequest::SearchClimate(ClimateSearchRequest {
                 city_id: id,
                 start_index: None,
                 max_items: None,
+                fields: None,
             })
         } else {
             CityRequest::SearchCity(CitySearchRequest {
                 query: req_str.into(),
                 start_index: None,
                 max_items: None,
+                fields: None,
+                autocomplete: None,
             })
         };
 
     Ok(simple_req)
 }
 
+/// Reads the `command` tag before deserializing to tell an unknown command from other errors
+pub fn request_from_value(value: serde_json::Value) -> Result<CityRequest, ApiError> {
+    let command = value.get("command")
+        .and_then(|it| it.as_str())
+        .map(str::to_owned);
+    serde_json::from_value(value)
+        .map_err(|e| ApiError::from_serde_message(&e.to_string(), command.as_deref()))
+}
+
+/// `city 123` or `city 123 456`
+fn parse_simple_city_ids(req_str: &str) -> Option<Vec<usize>> {
+    let ids = req_str.strip_prefix("city ")?
+        .split_whitespace()
+        .map(|it| it.parse().ok())
+        .collect::<Option<Vec<_>>>()?;
+    if ids.is_empty() { None } else { Some(ids) }
+}
 
-fn handle_request_impl<'a>(request: CityRequest) -> CityResponse<'a> {
+fn check_batch_items<'a>(requests: impl Iterator<Item = &'a CityRequest>, options: &RequestOptions) -> Result<(), ApiError> {
+    let total_items = requests
+        .map(|request| max_items(request, options))
+        .fold(0, usize::saturating_add);
+    match options.max_items_limit {
+        Some(limit) if total_items > limit => Err(
+            ApiError::new(ErrorCode::LimitExceeded, format!("The batch asks for {} items in total, the limit is {}", total_items, limit))
+        ),
+        _ => Ok(()),
+    }
+}
+
+/// How many items the request may answer with
+fn max_items(request: &CityRequest, options: &RequestOptions) -> usize {
     match request {
+        CityRequest::SearchCity(req) => req.max_items.unwrap_or(options.search_page_size),
+        CityRequest::SearchClimate(req) => req.max_items.unwrap_or(options.climate_page_size),
+        CityRequest::GetCity(_) => 1,
+        CityRequest::GetCities(req) => req.ids.len(),
+    }
+}
+
+fn check_len(len: usize, limit: Option<usize>, what: &str) -> Result<(), ApiError> {
+    match limit {
+        Some(limit) if len > limit => Err(
+            ApiError::new(ErrorCode::LimitExceeded, format!("{} has {} items, the limit is {}", what, len, limit))
+        ),
+        _ => Ok(()),
+    }
+}
+
+fn check_paging(start_index: Option<usize>, max_items: Option<usize>, options: &RequestOptions) -> Result<(), ApiError> {
+    let check = |value: Option<usize>, limit: Option<usize>, field: &str| match (value, limit) {
+        (Some(value), Some(limit)) if value > limit => Err(
+            ApiError::new(ErrorCode::LimitExceeded, format!("{} is {}, the limit is {}", field, value, limit)).with_field(field)
+        ),
+        _ => Ok(()),
+    };
+    check(start_index, options.start_index_limit, "startIndex")?;
+    check(max_items, options.max_items_limit, "maxItems")
+}
+
+fn handle_request_impl<'a>(data: &'a CachedData, request: CityRequest, options: &RequestOptions) -> Result<CityResponse<'a>, ApiError> {
+    let response = match request {
         CityRequest::SearchCity(req) => {
-            let cities = &CACHED_DATA.cities;
-            let search_data = &CACHED_DATA.search_data;
-            let city_search_query = make_search_query(&req.query);
-            let search_response = search_cities(
-                cities,
-                search_data,
-                &city_search_query,
-                req.start_index.unwrap_or(SEARCH_DEFAULT_START_INDEX),
-                req.max_items.unwrap_or(SEARCH_DEFAULT_MAX_ITEMS),
-            );
-            CityResponse::SearchCity(search_response)
+            CityResponse::SearchCity(search_city(data, &req, options, 1, |_| {})?)
         },
         CityRequest::SearchClimate(req) => {
-            let cities = &CACHED_DATA.cities;
-            let climate_search_data = &CACHED_DATA.climate_search_data;
-            let climate_search_response = search_climate(
+            check_paging(req.start_index, req.max_items, options)?;
+            let cities = &data.cities;
+            if req.city_id >= cities.len() {
+                return Err(ApiError::city_not_found(&[req.city_id], "cityId"));
+            }
+            let projection = make_projection(data, req.fields.as_deref())?;
+            let climate_search_data = &data.climate_search_data;
+            let started = Instant::now();
+            let mut climate_search_response = search_climate(
                 cities,
                 climate_search_data,
                 req.city_id,
                 req.start_index.unwrap_or(CLIMATE_DEFAULT_START_INDEX),
-                req.max_items.unwrap_or(CLIMATE_DEFAULT_MAX_ITEMS),
+                req.max_items.unwrap_or(options.climate_page_size),
+                &options.deadline,
             );
+            metrics::observe_search("climate", started.elapsed(), climate_search_response.items.len());
+            if let Some(projection) = projection {
+                for item in &mut climate_search_response.items {
+                    item.city = project_city(&cities[item.id], &projection);
+                }
+            }
             CityResponse::SearchClimate(climate_search_response)
         },
+        CityRequest::GetCity(req) => {
+            CityResponse::GetCity(get_city(data, req.id)?)
+        },
+        CityRequest::GetCities(req) => {
+            check_len(req.ids.len(), options.max_batch_len, "ids").map_err(|err| err.with_field("ids"))?;
+            let missing = req.ids.iter()
+                .copied()
+                .filter(|id| data.cities.get(*id).is_none())
+                .collect::<Vec<_>>();
+            if !missing.is_empty() {
+                return Err(ApiError::city_not_found(&missing, "ids"));
+            }
+            let items = req.ids.iter()
+                .map(|id| CityGetResponse { id: *id, city: &data.cities[*id] })
+                .collect();
+            CityResponse::GetCities(CitiesGetResponse { items })
+        },
+    };
+    Ok(response)
+}
+
+/// Partial responses are projected too, since they are sent as they are
+fn search_city<'a>(
+    data: &'a CachedData,
+    req: &CitySearchRequest,
+    options: &RequestOptions,
+    chunks: usize,
+    mut on_partial: impl FnMut(CitySearchResponse<'a>),
+) -> Result<CitySearchResponse<'a>, ApiError> {
+    check_paging(req.start_index, req.max_items, options)?;
+    let cities = &data.cities;
+    let projection = make_projection(data, req.fields.as_deref())?;
+    let project = |mut search_response: CitySearchResponse<'a>| {
+        if let Some(projection) = &projection {
+            for item in &mut search_response.items {
+                item.city = Some(project_city(&cities[item.id], projection));
+            }
+        }
+        search_response
+    };
+    let city_search_query = make_search_query(&req.query);
+    let started = Instant::now();
+    let start_index = req.start_index.unwrap_or(SEARCH_DEFAULT_START_INDEX);
+    let max_items = req.max_items.unwrap_or(options.search_page_size);
+    let search_response = if req.autocomplete.unwrap_or_default() {
+        autocomplete_cities(cities, &data.search_data, &city_search_query, start_index, max_items, &options.deadline)
+    } else {
+        search_cities_progressive(
+            cities,
+            &data.search_data,
+            &city_search_query,
+            start_index,
+            max_items,
+            &options.deadline,
+            chunks,
+            |partial| on_partial(project(partial)),
+        )
+    };
+    metrics::observe_search("city", started.elapsed(), search_response.items.len());
+    metrics::observe_cache_hit_rate_percent(search_response.cache_hit_rate_percent);
+    Ok(project(search_response))
+}
+
+/// Unknown field names are checked against the first city, so that a typo is an error
+/// rather than an empty object in every item
+fn make_projection(data: &CachedData, fields: Option<&[String]>) -> Result<Option<Projection>, ApiError> {
+    let Some(fields) = fields else {
+        return Ok(None);
+    };
+    let projection = Projection::parse(fields, "fields")?;
+    if let Some(sample) = data.cities.first() {
+        projection.validate(&serde_json::to_value(sample).unwrap())?;
+    }
+    Ok(Some(projection))
+}
+
+fn project_city<'a>(city: &City, projection: &Projection) -> ProjectedCity<'a> {
+    let mut fields = projection.apply(&serde_json::to_value(city).unwrap());
+    if let Some(climate) = fields.get_mut("climate") {
+        shorten_f32s(climate);
     }
+    ProjectedCity::Fields(fields)
+}
+
+/// Climate values are `f32`, which `to_value` widens to `f64`, printing 27.8 as 27.799999237060547.
+/// This gives each number the `f64` closest to the shortest decimal of its `f32`, which prints the same
+/// as serializing the city directly. Only the projected numbers are visited.
+fn shorten_f32s(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Number(number) => {
+            if let Some(shortened) = number.as_f64()
+                .filter(|_| number.is_f64())
+                .and_then(|it| (it as f32).to_string().parse().ok())
+                .and_then(serde_json::Number::from_f64)
+            {
+                *number = shortened;
+            }
+        },
+        serde_json::Value::Array(items) => items.iter_mut().for_each(shorten_f32s),
+        serde_json::Value::Object(map) => map.values_mut().for_each(shorten_f32s),
+        _ => {},
+    }
+}
+
+fn get_city(data: &CachedData, id: usize) -> Result<CityGetResponse<'_>, ApiError> {
+    data.cities.get(id)
+        .map(|city| CityGetResponse { id, city })
+        .ok_or_else(|| ApiError::city_not_found(&[id], "id"))
 }
 
-static CACHED_DATA: Lazy<CachedData> = Lazy::new(|| {
-    let cities = read_cities();
-    let search_data = make_search_data(&cities);
-    let climate_search_data = make_climate_search_data(&cities);
-    let data = CachedData { cities, search_data, climate_search_data };
-    eprintln_memory_usage();
-    data
-});
+static CACHED_DATA: ArcSwapOption<CachedData> = ArcSwapOption::const_empty();
 
-struct CachedData {
+/// Memory for cached results of each of the two searches, see `set_result_cache_bytes`
+static RESULT_CACHE_BYTES: AtomicUsize = AtomicUsize::new(result_cache::DEFAULT_MAX_BYTES);
+
+/// Applies to the data loaded afterwards, so binaries call it before `init_data`; 0 disables the caches
+pub fn set_result_cache_bytes(bytes: usize) {
+    RESULT_CACHE_BYTES.store(bytes, Ordering::Relaxed);
+}
+
+/// Only one reload at a time, so that a slow stale one doesn't overwrite a fresh one
+static RELOAD_LOCK: Mutex<()> = Mutex::new(());
+
+/// Cities with the indexes built over them, see `data_snapshot`
+pub struct CachedData {
     cities: Vec<City>,
+    version: String,
     search_data: CitySearchData,
     climate_search_data: ClimateSearchData,
 }
 
-macro_rules! to_str_items {
-    ($items: expr) => {
-        $items.into_iter()
-            .map(|it| serde_json::to_string(&it).unwrap())
-            .collect::<Vec<String>>()
-            .join("\n")
-    };
+impl CachedData {
+    /// Hash of the shards the data was read from
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    /// City names for tab completion, see `complete_city_names`
+    pub fn complete_city_name(&self, prefix: &str, max_items: usize) -> Vec<&str> {
+        complete_city_names(&self.cities, &self.search_data, prefix, max_items)
+    }
 }
 
-fn to_string_response<'a>(response: CityResponse<'a>, is_cli: bool) -> String {
-    if is_cli {
-        match response {
-            CityResponse::SearchCity(search_response) => {
-                let items_str = to_str_items!(search_response.items);
-                format!("{}\nelapsed_ms: {}, cache_hit_rate_percent: {}",
-                    items_str,
-                    search_response.elapsed_ms,
-                    search_response.cache_hit_rate_percent,
-                )
-            },
-            CityResponse::SearchClimate(climate_search_response) => {
-                let items_str = to_str_items!(climate_search_response.items);
-                format!("{}\nelapsed_ms: {}",
-                    items_str,
-                    climate_search_response.elapsed_ms,
-                )
-            }
-        }
-    } else {
-        serde_json::to_string(&response).unwrap()
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_error(req_str: &str) -> ApiError {
+        parse_request(req_str, false).unwrap_err()
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let bad_json = parse_error("{\"command\": ");
+        assert_eq!(ErrorCode::BadJson, bad_json.code);
+
+        let unknown_command = parse_error(r#"{"command": "searchCities", "query": "x"}"#);
+        assert_eq!(ErrorCode::UnknownCommand, unknown_command.code);
+        assert_eq!(Some("command".into()), unknown_command.field);
+
+        let unknown_field = parse_error(r#"{"command": "searchCity", "query": "x", "maxitems": 3}"#);
+        assert_eq!(ErrorCode::UnknownField, unknown_field.code);
+        assert_eq!(Some("maxitems".into()), unknown_field.field);
+
+        let missing_field = parse_error(r#"{"command": "searchClimate"}"#);
+        assert_eq!(ErrorCode::BadJson, missing_field.code);
+        assert_eq!(Some("cityId".into()), missing_field.field);
+
+        let wrong_type = parse_error(r#"{"command": "getCity", "id": "1"}"#);
+        assert_eq!(ErrorCode::BadJson, wrong_type.code);
+    }
+
+    #[test]
+    fn test_parse_simple() {
+        assert!(parse_request("123", false).is_err());
+        assert!(matches!(parse_request("123", true), Ok(CityRequest::SearchClimate(ClimateSearchRequest { city_id: 123, .. }))));
+        assert!(matches!(parse_request("city 1", true), Ok(CityRequest::GetCity(CityGetRequest { id: 1 }))));
+        assert!(matches!(parse_request("city 1 2", true), Ok(CityRequest::GetCities(_))));
+        assert!(matches!(parse_request("city x", true), Ok(CityRequest::SearchCity(_))));
+    }
+
+    #[test]
+    fn test_check_paging() {
+        let options = RequestOptions { max_items_limit: Some(100), start_index_limit: Some(1000), ..Default::default() };
+        assert!(check_paging(None, None, &options).is_ok());
+        assert!(check_paging(Some(1000), Some(100), &options).is_ok());
+
+        let err = check_paging(Some(0), Some(1_000_000_000), &options).unwrap_err();
+        assert_eq!(ErrorCode::LimitExceeded, err.code);
+        assert_eq!(Some("maxItems".into()), err.field);
+        assert_eq!(Some("startIndex".into()), check_paging(Some(1001), None, &options).unwrap_err().field);
+        assert!(check_paging(Some(usize::MAX), Some(usize::MAX), &RequestOptions::default()).is_ok());
+    }
+
+    #[test]
+    fn test_shorten_f32s() {
+        let mut value = serde_json::to_value([Some(27.8_f32), None, Some(-0.1), Some(1e-7)]).unwrap();
+        assert_eq!("[27.799999237060547,null,-0.10000000149011612,1.0000000116860974e-7]", value.to_string());
+        shorten_f32s(&mut value);
+        assert_eq!(serde_json::to_string(&[Some(27.8_f32), None, Some(-0.1), Some(1e-7)]).unwrap(), value.to_string());
+    }
+
+    #[test]
+    fn test_batch_limits() {
+        let options = RequestOptions { max_items_limit: Some(100), ..Default::default() };
+        let requests = |reqs: &[&str]| reqs.iter().map(|it| parse_request(it, false).unwrap()).collect::<Vec<_>>();
+
+        let small = requests(&[r#"{"command": "getCity", "id": 1}"#, r#"{"command": "getCities", "ids": [1, 2, 3]}"#, r#"{"command": "searchCity", "query": "x"}"#]);
+        assert!(check_batch_items(small.iter(), &options).is_ok());
+        let large = requests(&[r#"{"command": "searchClimate", "cityId": 1, "maxItems": 60}"#, r#"{"command": "searchClimate", "cityId": 1}"#]);
+        assert_eq!(ErrorCode::LimitExceeded, check_batch_items(large.iter(), &options).unwrap_err().code);
+        assert!(check_batch_items(large.iter(), &RequestOptions::default()).is_ok());
+
+        assert!(check_len(3, Some(3), "ids").is_ok());
+        assert_eq!(ErrorCode::LimitExceeded, check_len(4, Some(3), "ids").unwrap_err().code);
+        assert!(check_len(usize::MAX, None, "ids").is_ok());
     }
 }
@@ -15,6 +15,33 @@ pub fn jaro_winkler_vec<T: Eq>(a: &Vec<T>, b: &Vec<T>) -> f32 {
     }
 }
 
+/// Upper bound of `jaro_winkler_vec` of two strings, without comparing them: from their lengths, the number
+/// of characters they have in common wherever they are, counting repeated characters as often as both
+/// strings have them, and the length of their common prefix.
+///
+/// ```
+/// use backend::library::jaro::{jaro_winkler_upper_bound, jaro_winkler_vec};
+///
+/// let (a, b) = ("martha".chars().collect(), "marhta".chars().collect());
+/// assert!(jaro_winkler_vec(&a, &b) <= jaro_winkler_upper_bound(6, 6, 6, 3));
+/// assert_eq!(0.0, jaro_winkler_upper_bound(6, 4, 0, 0));
+/// ```
+pub fn jaro_winkler_upper_bound(a_len: usize, b_len: usize, common: usize, prefix_len: usize) -> f32 {
+    if a_len == 0 && b_len == 0 {
+        return 1.0;
+    }
+    if common == 0 {
+        return 0.0;
+    }
+    // As in `jaro_vec`, with no transpositions and every common character matching
+    let sim = ((common as f32 / a_len as f32) + (common as f32 / b_len as f32) + 1.0) / 3.0;
+    if sim > 0.69999 {
+        sim + 0.1 * prefix_len.min(4) as f32 * (1.0 - sim)
+    } else {
+        sim
+    }
+}
+
 /// Copied from strsim::generic_jaro and optimized for Vec.
 /// See https://docs.rs/strsim/latest/strsim/fn.generic_jaro.html
 pub fn jaro_vec<T: Eq>(a: &Vec<T>, b: &Vec<T>) -> f32 {
@@ -148,6 +175,20 @@ mod tests {
         })
     }
 
+    #[test]
+    fn test_upper_bound() {
+        (0..100_000).into_par_iter().for_each(|_| {
+            let (a, b) = (v(&make_random_str()), v(&make_random_str()));
+            let mut b_rest = b.clone();
+            let common = a.iter()
+                .filter(|c| b_rest.iter().position(|it| it == *c).map(|i| b_rest.swap_remove(i)).is_some())
+                .count();
+            let prefix_len = a.iter().zip(&b).take_while(|(a, b)| a == b).count();
+            let bound = jaro_winkler_upper_bound(a.len(), b.len(), common, prefix_len);
+            assert!(jaro_winkler_vec(&a, &b) <= bound, "a={:?}, b={:?}", a, b);
+        })
+    }
+
     const RND_CHARS: [char; 11] = ['a', 'b', 'c', 'd', 'e', 'f', 'g', 'h', 'i', 'j', ' '];
     const MAX_LEN: usize = 15;
 
@@ -0,0 +1,75 @@
+use crate::library::{api::CityRequest, api_error::*, handle_request::request_from_value};
+use serde_json::{Map, Value};
+
+/// Key of the id a request of the JSON lines protocol may have, echoed back in its response
+pub const REQUEST_ID_KEY: &str = "requestId";
+
+/// Parses a line of the CLI's JSON lines protocol: a request object as for `POST /`, with an optional
+/// `requestId` of any JSON type. The id is returned even if the request is invalid, so that the error
+/// can be told apart; it is null if missing.
+///
+/// ```
+/// use backend::library::{api::CityRequest, jsonl::parse_line};
+///
+/// let (request_id, request) = parse_line(r#"{"requestId": "a", "command": "getCity", "id": 5}"#);
+/// assert_eq!("a", request_id);
+/// assert!(matches!(request, Ok(CityRequest::GetCity(_))));
+/// ```
+pub fn parse_line(line: &str) -> (Value, Result<CityRequest, ApiError>) {
+    let mut value = match serde_json::from_str::<Value>(line) {
+        Ok(value) => value,
+        Err(e) => return (Value::Null, Err(ApiError::new(ErrorCode::BadJson, e.to_string()))),
+    };
+    let Some(fields) = value.as_object_mut() else {
+        return (Value::Null, Err(ApiError::new(ErrorCode::BadJson, "Expected a request object, one per line")));
+    };
+    let request_id = fields.remove(REQUEST_ID_KEY).unwrap_or_default();
+    (request_id, request_from_value(value))
+}
+
+/// The response line of a request: `requestId` followed by the fields of the JSON `response`, or by `error`
+pub fn format_response(request_id: Value, response: Result<Vec<u8>, ApiError>) -> String {
+    let mut line = Map::new();
+    line.insert(REQUEST_ID_KEY.to_owned(), request_id);
+    match response {
+        Ok(bytes) => match serde_json::from_slice(&bytes).unwrap() {
+            Value::Object(fields) => line.extend(fields),
+            other => panic!("Expected a response object, got {}", other),
+        },
+        Err(error) => {
+            line.insert("error".to_owned(), serde_json::to_value(&error).unwrap());
+        },
+    }
+    Value::Object(line).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_errors() {
+        let (request_id, request) = parse_line(r#"{"requestId": 1, "command": "getCity", "id": 5, "ids": []}"#);
+        assert_eq!(json!(1), request_id);
+        assert_eq!(ErrorCode::UnknownField, request.unwrap_err().code);
+
+        for line in ["{", "[]", r#""getCity""#] {
+            let (request_id, request) = parse_line(line);
+            assert_eq!(Value::Null, request_id);
+            assert_eq!(ErrorCode::BadJson, request.unwrap_err().code, "{}", line);
+        }
+    }
+
+    #[test]
+    fn test_format_response() {
+        let response = br#"{"command":"getCity","id":5,"city":{}}"#.to_vec();
+        assert_eq!(r#"{"requestId":"a","command":"getCity","id":5,"city":{}}"#, format_response(json!("a"), Ok(response)));
+
+        let error = ApiError::city_not_found(&[5], "id");
+        assert_eq!(
+            r#"{"requestId":null,"error":{"code":"cityNotFound","message":"City not found: 5","field":"id"}}"#,
+            format_response(Value::Null, Err(error)),
+        );
+    }
+}
@@ -0,0 +1,149 @@
+use crate::library::api_error::ApiError;
+use once_cell::sync::Lazy;
+use prometheus::{Encoder, Gauge, Histogram, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder};
+use std::time::Duration;
+
+const NAMESPACE: &str = "somewhere_like";
+
+/// Content type of `gather()` output
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Label for requests which could not be parsed, so their command is unknown
+pub const INVALID_COMMAND: &str = "invalid";
+
+struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    search_duration: HistogramVec,
+    search_results: HistogramVec,
+    cache_hit_ratio: Histogram,
+    result_cache_lookups: IntCounterVec,
+    result_cache_entries: IntGaugeVec,
+    result_cache_bytes: IntGaugeVec,
+    dataset_cities: IntGauge,
+    dataset_load_seconds: Gauge,
+}
+
+static METRICS: Lazy<Metrics> = Lazy::new(|| {
+    let registry = Registry::new_custom(Some(NAMESPACE.into()), None).unwrap();
+
+    let requests = IntCounterVec::new(
+        Opts::new("requests_total", "Handled commands by command and status, which is \"ok\" or an error code"),
+        &["command", "status"],
+    ).unwrap();
+    let search_duration = HistogramVec::new(
+        HistogramOpts::new("search_duration_seconds", "Time spent in search_cities and search_climate")
+            .buckets(vec![0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5]),
+        &["search"],
+    ).unwrap();
+    let search_results = HistogramVec::new(
+        HistogramOpts::new("search_results", "Number of items in a search response")
+            .buckets(vec![0.0, 1.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 1000.0]),
+        &["search"],
+    ).unwrap();
+    let cache_hit_ratio = Histogram::with_opts(
+        HistogramOpts::new("jaro_winkler_cache_hit_ratio", "Share of Jaro-Winkler similarities taken from the cache, per searchCity")
+            .buckets(prometheus::linear_buckets(0.1, 0.1, 10).unwrap()),
+    ).unwrap();
+    let result_cache_lookups = IntCounterVec::new(
+        Opts::new("result_cache_lookups_total", "Lookups in the search result cache by search and result, which is \"hit\" or \"miss\""),
+        &["search", "result"],
+    ).unwrap();
+    let result_cache_entries = IntGaugeVec::new(
+        Opts::new("result_cache_entries", "Entries in the search result cache"),
+        &["search"],
+    ).unwrap();
+    let result_cache_bytes = IntGaugeVec::new(
+        Opts::new("result_cache_bytes", "Estimated memory of the search result cache"),
+        &["search"],
+    ).unwrap();
+    let dataset_cities = IntGauge::new("dataset_cities", "Number of cities in the served dataset").unwrap();
+    let dataset_load_seconds = Gauge::new("dataset_load_seconds", "Time to read the shards and build the indexes of the served dataset").unwrap();
+
+    registry.register(Box::new(requests.clone())).unwrap();
+    registry.register(Box::new(search_duration.clone())).unwrap();
+    registry.register(Box::new(search_results.clone())).unwrap();
+    registry.register(Box::new(cache_hit_ratio.clone())).unwrap();
+    registry.register(Box::new(result_cache_lookups.clone())).unwrap();
+    registry.register(Box::new(result_cache_entries.clone())).unwrap();
+    registry.register(Box::new(result_cache_bytes.clone())).unwrap();
+    registry.register(Box::new(dataset_cities.clone())).unwrap();
+    registry.register(Box::new(dataset_load_seconds.clone())).unwrap();
+
+    Metrics {
+        registry,
+        requests,
+        search_duration,
+        search_results,
+        cache_hit_ratio,
+        result_cache_lookups,
+        result_cache_entries,
+        result_cache_bytes,
+        dataset_cities,
+        dataset_load_seconds,
+    }
+});
+
+/// `error` is None for a successful request
+pub fn observe_request(command: &str, error: Option<&ApiError>) {
+    let status = match error {
+        None => "ok".to_owned(),
+        Some(err) => serde_json::to_value(err.code).unwrap().as_str().unwrap().to_owned(),
+    };
+    METRICS.requests.with_label_values(&[command, &status]).inc();
+}
+
+/// `search` is `city` or `climate`
+pub fn observe_search(search: &str, duration: Duration, results: usize) {
+    METRICS.search_duration.with_label_values(&[search]).observe(duration.as_secs_f64());
+    METRICS.search_results.with_label_values(&[search]).observe(results as f64);
+}
+
+pub fn observe_cache_hit_rate_percent(percent: f32) {
+    // NaN if nothing was looked up
+    if percent.is_finite() {
+        METRICS.cache_hit_ratio.observe(percent as f64 / 100.0);
+    }
+}
+
+pub fn observe_result_cache(search: &str, hit: bool) {
+    METRICS.result_cache_lookups.with_label_values(&[search, if hit { "hit" } else { "miss" }]).inc();
+}
+
+pub fn set_result_cache_size(search: &str, entries: usize, bytes: usize) {
+    METRICS.result_cache_entries.with_label_values(&[search]).set(entries as i64);
+    METRICS.result_cache_bytes.with_label_values(&[search]).set(bytes as i64);
+}
+
+pub fn set_dataset(cities: usize, load_time: Duration) {
+    METRICS.dataset_cities.set(cities as i64);
+    METRICS.dataset_load_seconds.set(load_time.as_secs_f64());
+}
+
+/// All metrics in the Prometheus text format
+pub fn gather() -> String {
+    let mut buf = Vec::new();
+    TextEncoder::new().encode(&METRICS.registry.gather(), &mut buf).unwrap();
+    String::from_utf8(buf).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gather() {
+        observe_request("getCity", None);
+        observe_request("getCity", Some(&ApiError::city_not_found(&[1], "id")));
+        observe_search("climate", Duration::from_millis(3), 100);
+        observe_cache_hit_rate_percent(f32::NAN);
+        observe_result_cache("gather", true);
+
+        let text = gather();
+        assert!(text.contains("somewhere_like_requests_total{command=\"getCity\",status=\"ok\"} 1"), "{}", text);
+        assert!(text.contains("somewhere_like_requests_total{command=\"getCity\",status=\"cityNotFound\"} 1"), "{}", text);
+        assert!(text.contains("somewhere_like_search_results_bucket{search=\"climate\",le=\"100\"} 1"), "{}", text);
+        assert!(text.contains("somewhere_like_jaro_winkler_cache_hit_ratio_count 0"), "{}", text);
+        assert!(text.contains("somewhere_like_result_cache_lookups_total{result=\"hit\",search=\"gather\"} 1"), "{}", text);
+    }
+}
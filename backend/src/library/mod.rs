@@ -1,10 +1,27 @@
 pub mod api;
+pub mod api_error;
+pub mod char_index;
+pub mod cli_output;
 pub mod climate_search;
+pub mod compression;
+pub mod deadline;
 pub mod earth;
+pub mod format;
 pub mod handle_request;
 pub mod intern;
 pub mod jaro;
+pub mod jsonl;
+pub mod metrics;
 pub mod minmax;
+pub mod normalize;
+pub mod projection;
+pub mod rate_limit;
+pub mod repl;
+pub mod result_cache;
+pub mod router;
+pub mod schema;
 pub mod search;
+pub mod server_config;
 pub mod split;
-pub mod test_util;
\ No newline at end of file
+pub mod test_util;
+pub mod tls;
\ No newline at end of file
@@ -0,0 +1,90 @@
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+/// Form of city names and queries in the search index: lowercase, compatibility-decomposed (NFKD) without
+/// diacritics, and with letters which don't decompose, such as `ß` or `ø`, spelled in basic Latin.
+/// So "zurich" matches "Zürich" exactly, not just by Jaro-Winkler similarity.
+///
+/// ```
+/// use backend::library::normalize::normalize;
+///
+/// assert_eq!("sao paulo", normalize("São Paulo"));
+/// assert_eq!("malmo", normalize("Malmö"));
+/// assert_eq!("grossglockner", normalize("GROẞGLOCKNER"));
+/// assert_eq!("lodz", normalize("Łódź"));
+/// ```
+pub fn normalize(text: &str) -> String {
+    let mut normalized = String::with_capacity(text.len());
+    let chars = text.nfkd()
+        .flat_map(char::to_lowercase)
+        .filter(|c| !is_combining_mark(*c));
+    for c in chars {
+        match c {
+            'ß' => normalized.push_str("ss"),
+            'æ' => normalized.push_str("ae"),
+            'œ' => normalized.push_str("oe"),
+            'þ' => normalized.push_str("th"),
+            'ø' => normalized.push('o'),
+            'ł' => normalized.push('l'),
+            'đ' | 'ð' => normalized.push('d'),
+            'ħ' => normalized.push('h'),
+            'ı' => normalized.push('i'),
+            'ŧ' => normalized.push('t'),
+            // Final sigma, which a query typed in lowercase may lack
+            'ς' => normalized.push('σ'),
+            _ => normalized.push(c),
+        }
+    }
+    normalized
+}
+
+/// Latin form of `text` in any script, normalized: "Москва" gives "moskva", "Αθήνα" "athina" and "서울"
+/// "seoul". Chinese characters are romanized as Mandarin pinyin, also in Japanese names.
+///
+/// ```
+/// use backend::library::normalize::transliterate;
+///
+/// assert_eq!("moskva", transliterate("Москва"));
+/// assert_eq!("zurich", transliterate("Zürich"));
+/// ```
+pub fn transliterate(text: &str) -> String {
+    normalize(&any_ascii::any_ascii(text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        for (text, expected) in [
+            ("Zürich", "zurich"),
+            ("Straße", "strasse"),
+            ("København", "kobenhavn"),
+            ("Þórshöfn", "thorshofn"),
+            ("İstanbul", "istanbul"),
+            ("Đà Nẵng", "da nang"),
+            ("Ｔｏｋｙｏ", "tokyo"),
+            ("ΑΘΗΝΑΣ", "αθηνασ"),
+            ("Αθήνας", "αθηνασ"),
+            ("東京", "東京"),
+            ("", ""),
+        ] {
+            assert_eq!(expected, normalize(text), "{}", text);
+        }
+    }
+
+    #[test]
+    fn test_transliterate() {
+        for (text, expected) in [
+            ("Санкт-Петербург", "sankt-peterburg"),
+            ("Αθήνα", "athina"),
+            ("القاهرة", "lqhrh"),
+            ("תל אביב", "tl 'vyv"),
+            ("北京", "beijing"),
+            ("서울", "seoul"),
+            ("København", "kobenhavn"),
+        ] {
+            assert_eq!(expected, transliterate(text), "{}", text);
+        }
+    }
+}
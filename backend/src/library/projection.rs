@@ -0,0 +1,205 @@
+use crate::library::api_error::*;
+use serde_json::{Map, Value};
+
+/// Subset of a JSON value selected by paths like `names[0]`, `latitude` or `climate.tmaxMonthly`.
+/// The result keeps the nesting: `climate.tmaxMonthly` gives `{"climate": {"tmaxMonthly": [...]}}`,
+/// and indexes pick elements in the requested order, so `names[0]` gives `{"names": ["Tokyo"]}`.
+/// Picked elements are not kept at their positions: `climate.tmaxMonthly[6]` gives a one-element array.
+/// Missing array elements are omitted. Each value is selected once, however many paths lead to it, and a
+/// path selects the whole value even if paths into it are given too.
+#[derive(Debug, PartialEq)]
+pub struct Projection {
+    paths: Vec<Vec<Segment>>,
+    tree: Node,
+}
+
+/// The paths merged, so that each value is visited once
+#[derive(Debug, Default, PartialEq)]
+struct Node {
+    /// The whole value is selected, whatever the children
+    whole: bool,
+    /// In the order of the first path through each
+    children: Vec<(Segment, Node)>,
+}
+
+impl Node {
+    fn insert(&mut self, path: &[Segment]) {
+        if self.whole {
+            return;
+        }
+        let Some((segment, rest)) = path.split_first() else {
+            self.whole = true;
+            self.children.clear();
+            return;
+        };
+        let child = match self.children.iter().position(|(it, _)| it == segment) {
+            Some(i) => &mut self.children[i].1,
+            None => {
+                self.children.push((segment.clone(), Node::default()));
+                &mut self.children.last_mut().unwrap().1
+            },
+        };
+        child.insert(rest);
+    }
+
+    /// None if none of the paths exist in `value`
+    fn project(&self, value: &Value) -> Option<Value> {
+        if self.whole {
+            return Some(value.clone());
+        }
+        let projected = match value {
+            Value::Object(map) => Value::Object(self.children.iter()
+                .filter_map(|(segment, child)| match segment {
+                    Segment::Key(key) => Some((key.clone(), child.project(map.get(key)?)?)),
+                    Segment::Index(_) => None,
+                })
+                .collect::<Map<_, _>>()),
+            Value::Array(items) => Value::Array(self.children.iter()
+                .filter_map(|(segment, child)| match segment {
+                    Segment::Index(index) => child.project(items.get(*index)?),
+                    Segment::Key(_) => None,
+                })
+                .collect()),
+            _ => return None,
+        };
+        let is_empty = projected.as_object().is_some_and(Map::is_empty) || projected.as_array().is_some_and(Vec::is_empty);
+        Some(projected).filter(|_| !is_empty)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+impl Projection {
+    /// `field` is the request field to report in errors
+    ///
+    /// ```
+    /// use backend::library::projection::Projection;
+    /// use serde_json::json;
+    ///
+    /// let fields = ["names[0]".into(), "climate.tmax".into()];
+    /// let projection = Projection::parse(&fields, "fields").unwrap();
+    /// let city = json!({"names": ["Tokyo", "東京"], "latitude": 35.7, "climate": {"tmax": [9.8], "tmin": [1.2]}});
+    /// assert_eq!(json!({"names": ["Tokyo"], "climate": {"tmax": [9.8]}}), projection.apply(&city));
+    /// ```
+    pub fn parse(fields: &[String], field: &str) -> Result<Projection, ApiError> {
+        let paths = fields.iter()
+            .map(|path| parse_path(path).ok_or_else(|| {
+                ApiError::new(ErrorCode::BadJson, format!("Invalid path \"{}\", expected like \"names[0]\" or \"climate.tmaxMonthly\"", path))
+                    .with_field(field)
+            }))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut tree = Node::default();
+        for path in &paths {
+            tree.insert(path);
+        }
+        Ok(Projection { paths, tree })
+    }
+
+    /// Checks that every key in the paths exists in `sample`, reporting the first unknown one
+    pub fn validate(&self, sample: &Value) -> Result<(), ApiError> {
+        for path in &self.paths {
+            let mut value = sample;
+            for (i, segment) in path.iter().enumerate() {
+                value = match (segment, value) {
+                    (Segment::Key(key), Value::Object(map)) if map.contains_key(key) => &map[key],
+                    (Segment::Index(index), Value::Array(items)) => match items.get(*index) {
+                        Some(item) => item,
+                        None => break,
+                    },
+                    _ => return Err(ApiError::new(
+                        ErrorCode::UnknownField,
+                        format!("Unknown field \"{}\"", path_to_string(&path[..=i])),
+                    ).with_field(path_to_string(path))),
+                };
+            }
+        }
+        Ok(())
+    }
+
+    pub fn apply(&self, value: &Value) -> Value {
+        self.tree.project(value).unwrap_or_else(|| Value::Object(Map::new()))
+    }
+}
+
+fn parse_path(path: &str) -> Option<Vec<Segment>> {
+    let mut segments = Vec::new();
+    for part in path.split('.') {
+        let (key, mut rest) = part.split_at(part.find('[').unwrap_or(part.len()));
+        if key.is_empty() || !key.chars().all(|c| c.is_alphanumeric() || c == '_') {
+            return None;
+        }
+        segments.push(Segment::Key(key.into()));
+        while !rest.is_empty() {
+            let (index, after) = rest.strip_prefix('[')?.split_once(']')?;
+            segments.push(Segment::Index(index.parse().ok()?));
+            rest = after;
+        }
+    }
+    Some(segments)
+}
+
+fn path_to_string(path: &[Segment]) -> String {
+    path.iter().enumerate()
+        .map(|(i, segment)| match segment {
+            Segment::Key(key) if i == 0 => key.clone(),
+            Segment::Key(key) => format!(".{}", key),
+            Segment::Index(index) => format!("[{}]", index),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn projection(fields: &[&str]) -> Result<Projection, ApiError> {
+        Projection::parse(&fields.iter().map(|it| it.to_string()).collect::<Vec<_>>(), "fields")
+    }
+
+    #[test]
+    fn test_parse() {
+        assert!(projection(&["names[0]", "climate.tmaxMonthly[11]", "a[1][2]"]).is_ok());
+        for bad in ["", "names[", "names[x]", "names]", ".latitude", "climate..tmax", "[0]"] {
+            assert_eq!(ErrorCode::BadJson, projection(&[bad]).unwrap_err().code, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        let city = json!({"names": ["Tokyo"], "adminUnit": null, "climate": {"tmax": [1.0]}});
+        assert!(projection(&["names[5]", "adminUnit", "climate.tmax[0]"]).unwrap().validate(&city).is_ok());
+
+        let err = projection(&["climate.tmin[0]"]).unwrap().validate(&city).unwrap_err();
+        assert_eq!(ErrorCode::UnknownField, err.code);
+        assert_eq!(Some("climate.tmin[0]".into()), err.field);
+
+        assert!(projection(&["names.first"]).unwrap().validate(&city).is_err());
+    }
+
+    #[test]
+    fn test_apply() {
+        let city = json!({"names": ["a", "b", "c"], "latitude": 1.5, "climate": {"tmax": [1, 2], "tmin": [3, 4]}});
+        assert_eq!(
+            json!({"names": ["c", "a"], "latitude": 1.5, "climate": {"tmax": [1, 2], "tmin": [4]}}),
+            projection(&["names[2]", "latitude", "names[0]", "names[9]", "climate.tmax", "climate.tmin[1]"]).unwrap().apply(&city),
+        );
+        assert_eq!(json!({}), projection(&[]).unwrap().apply(&city));
+        assert_eq!(json!({}), projection(&["names[9]"]).unwrap().apply(&city));
+    }
+
+    #[test]
+    fn test_apply_overlapping() {
+        let city = json!({"names": ["a", "b"], "climate": {"tmax": [1, 2, 3]}, "items": [{"x": 1, "y": 2, "z": 3}]});
+        let whole = json!({"climate": {"tmax": [1, 2, 3]}});
+        assert_eq!(whole, projection(&["climate.tmax", "climate.tmax[0]"]).unwrap().apply(&city));
+        assert_eq!(whole, projection(&["climate.tmax[0]", "climate.tmax"]).unwrap().apply(&city));
+        assert_eq!(whole, projection(&["climate.tmax[1]", "climate"]).unwrap().apply(&city));
+        assert_eq!(json!({"names": ["a"]}), projection(&["names[0]", "names[0]"]).unwrap().apply(&city));
+        assert_eq!(json!({"items": [{"x": 1, "z": 3}]}), projection(&["items[0].x", "items[0].z"]).unwrap().apply(&city));
+    }
+}
@@ -0,0 +1,219 @@
+use dashmap::DashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// Buckets are pruned no more often than when the map reaches this size
+const MIN_PRUNE_LEN: usize = 1024;
+
+/// Token bucket per client: each request takes a token, tokens refill at `per_sec` up to `burst`.
+/// IPv6 clients are keyed by their /64 network, since one host usually gets a whole /64.
+///
+/// ```
+/// use backend::library::rate_limit::RateLimiter;
+/// use std::net::IpAddr;
+///
+/// let limiter = RateLimiter::new(1.0, 2);
+/// let ip = IpAddr::from([10, 0, 0, 1]);
+/// assert!(limiter.check(ip).is_ok());
+/// assert!(limiter.check(ip).is_ok());
+/// assert!(limiter.check(ip).is_err());
+/// assert!(limiter.check(IpAddr::from([10, 0, 0, 2])).is_ok());
+/// ```
+#[derive(Debug)]
+pub struct RateLimiter {
+    per_sec: f64,
+    burst: f64,
+    buckets: DashMap<IpAddr, Bucket>,
+    prune_at_len: AtomicUsize,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn tokens_at(&self, now: Instant, per_sec: f64, burst: f64) -> f64 {
+        let refilled = now.saturating_duration_since(self.updated).as_secs_f64() * per_sec;
+        (self.tokens + refilled).min(burst)
+    }
+}
+
+impl RateLimiter {
+    pub fn new(per_sec: f64, burst: u32) -> RateLimiter {
+        RateLimiter {
+            per_sec,
+            burst: burst.max(1) as f64,
+            buckets: DashMap::new(),
+            prune_at_len: AtomicUsize::new(MIN_PRUNE_LEN),
+        }
+    }
+
+    /// Takes a token, or returns how long to wait for the next one
+    pub fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+        self.check_at(ip, Instant::now())
+    }
+
+    fn check_at(&self, ip: IpAddr, now: Instant) -> Result<(), Duration> {
+        let result = {
+            let mut bucket = self.buckets.entry(bucket_key(ip))
+                .or_insert(Bucket { tokens: self.burst, updated: now });
+            let tokens = bucket.tokens_at(now, self.per_sec, self.burst);
+            *bucket = Bucket { tokens: (tokens - 1.0).max(0.0), updated: now };
+            if tokens >= 1.0 {
+                Ok(())
+            } else {
+                Err(Duration::from_secs_f64((1.0 - tokens) / self.per_sec))
+            }
+        };
+        self.prune(now);
+        result
+    }
+
+    /// Full buckets are the same as missing ones, so they are dropped once the map grows
+    fn prune(&self, now: Instant) {
+        if self.buckets.len() < self.prune_at_len.load(Ordering::Relaxed) {
+            return;
+        }
+        self.buckets.retain(|_, bucket| bucket.tokens_at(now, self.per_sec, self.burst) < self.burst);
+        self.prune_at_len.store((2 * self.buckets.len()).max(MIN_PRUNE_LEN), Ordering::Relaxed);
+    }
+}
+
+/// IP of the client: the peer, unless it is a trusted proxy, in which case the nearest address in
+/// `Forwarded` (or else `X-Forwarded-For`) which is not a trusted proxy. The addresses left of it
+/// are not believed, since the client could have sent them.
+///
+/// ```
+/// use backend::library::rate_limit::client_ip;
+/// use std::net::IpAddr;
+///
+/// let proxy = IpAddr::from([10, 0, 0, 1]);
+/// let client = IpAddr::from([203, 0, 113, 7]);
+/// assert_eq!(client, client_ip(proxy, None, Some("192.0.2.1, 203.0.113.7"), &[proxy]));
+/// assert_eq!(client, client_ip(client, None, Some("192.0.2.1"), &[proxy]));
+/// ```
+pub fn client_ip(peer_ip: IpAddr, forwarded: Option<&str>, x_forwarded_for: Option<&str>, trusted_proxies: &[IpAddr]) -> IpAddr {
+    if !trusted_proxies.contains(&peer_ip) {
+        return peer_ip;
+    }
+    let chain = match forwarded {
+        Some(forwarded) => forwarded.split(',')
+            .filter_map(|element| element.split(';')
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(name, _)| name.trim().eq_ignore_ascii_case("for"))
+                .and_then(|(_, node)| parse_node(node)))
+            .collect::<Vec<_>>(),
+        None => x_forwarded_for.into_iter()
+            .flat_map(|it| it.split(','))
+            .filter_map(parse_node)
+            .collect(),
+    };
+    chain.iter().rev()
+        .find(|ip| !trusted_proxies.contains(ip))
+        .or(chain.first())
+        .copied()
+        .unwrap_or(peer_ip)
+}
+
+/// `192.0.2.1`, `192.0.2.1:4711`, `2001:db8::1` or `"[2001:db8::1]:4711"`; None for `unknown` and obfuscated ones
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Ok(ip) = node.parse() {
+        return Some(ip);
+    }
+    match node.strip_prefix('[') {
+        Some(bracketed) => bracketed.split_once(']')?.0.parse().ok(),
+        None => node.rsplit_once(':')?.0.parse().ok(),
+    }
+}
+
+fn bucket_key(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => IpAddr::V6((u128::from(v6) & !0 << 64).into()),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_refill() {
+        let limiter = RateLimiter::new(2.0, 3);
+        let ip = IpAddr::from([10, 0, 0, 1]);
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert!(limiter.check_at(ip, start).is_ok());
+        }
+        assert_eq!(Err(Duration::from_millis(500)), limiter.check_at(ip, start));
+
+        let later = start + Duration::from_millis(500);
+        assert!(limiter.check_at(ip, later).is_ok());
+        assert!(limiter.check_at(ip, later).is_err());
+
+        // Refills up to the burst only
+        let much_later = later + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(limiter.check_at(ip, much_later).is_ok());
+        }
+        assert!(limiter.check_at(ip, much_later).is_err());
+    }
+
+    #[test]
+    fn test_ipv6_network() {
+        let limiter = RateLimiter::new(1.0, 1);
+        let a = "2001:db8:0:1::1".parse().unwrap();
+        let b = "2001:db8:0:1::2".parse().unwrap();
+        let other_network = "2001:db8:0:2::1".parse().unwrap();
+        assert!(limiter.check(a).is_ok());
+        assert!(limiter.check(b).is_err());
+        assert!(limiter.check(other_network).is_ok());
+
+        let mapped = "::ffff:10.0.0.1".parse().unwrap();
+        assert!(limiter.check(mapped).is_ok());
+        assert!(limiter.check(IpAddr::from([10, 0, 0, 1])).is_err());
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let proxy = IpAddr::from([10, 0, 0, 1]);
+        let other_proxy = IpAddr::from([10, 0, 0, 2]);
+        let trusted = [proxy, other_proxy];
+        let client = IpAddr::from([203, 0, 113, 7]);
+
+        assert_eq!(proxy, client_ip(proxy, None, None, &trusted));
+        assert_eq!(client, client_ip(proxy, None, Some("203.0.113.7"), &trusted));
+        assert_eq!(client, client_ip(proxy, None, Some("1.2.3.4, 203.0.113.7, 10.0.0.2"), &trusted));
+        assert_eq!(other_proxy, client_ip(proxy, None, Some("10.0.0.2"), &trusted));
+        assert_eq!(client, client_ip(proxy, Some("for=1.2.3.4, for=\"203.0.113.7:4711\";proto=https"), Some("5.6.7.8"), &trusted));
+        assert_eq!(
+            "2001:db8::1".parse::<IpAddr>().unwrap(),
+            client_ip(proxy, Some("proto=http;For=\"[2001:db8::1]:4711\""), None, &trusted),
+        );
+        assert_eq!(proxy, client_ip(proxy, Some("for=unknown"), None, &trusted));
+        assert_eq!(client, client_ip(client, None, Some("1.2.3.4"), &trusted));
+    }
+
+    #[test]
+    fn test_prune() {
+        let limiter = RateLimiter::new(1.0, 1);
+        let start = Instant::now();
+        for i in 1..MIN_PRUNE_LEN as u32 {
+            limiter.check_at(IpAddr::from(i.to_be_bytes()), start).unwrap();
+        }
+        assert_eq!(MIN_PRUNE_LEN - 1, limiter.buckets.len());
+
+        // By now the others are full again
+        limiter.check_at(IpAddr::from([0, 0, 0, 0]), start + Duration::from_secs(1)).unwrap();
+        assert_eq!(1, limiter.buckets.len());
+        assert!(limiter.check_at(IpAddr::from([0, 0, 0, 0]), start + Duration::from_secs(1)).is_err());
+    }
+}
@@ -0,0 +1,213 @@
+use crate::library::{cli_output::{write_columns, OutputFormat}, earth::get_arc_distance_km, handle_request::CachedData};
+use clap::ValueEnum;
+use rustyline::{completion::Completer, Context, Helper, Highlighter, Hinter, Validator};
+use serde_json::Value;
+use std::{io::{self, Write}, sync::Arc};
+
+pub const HELP: &str = "\
+Enter a city name to search by name, an id to search by climate, \"city <id> [<id>...]\" to show cities, or a JSON request.
+  :show <id>              the city with its monthly climate
+  :compare <id> <id>      two cities side by side
+  :set                    show the settings
+  :set max <n>            number of search results
+  :set format <format>    table, jsonl or csv
+  :timing on|off          show how long each request takes
+  :help, :quit";
+
+const COMMANDS: [&str; 6] = [":show", ":compare", ":set", ":timing", ":help", ":quit"];
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+const MAX_COMPLETIONS: usize = 20;
+
+/// A line of the interactive CLI
+#[derive(Debug, PartialEq)]
+pub enum ReplCommand {
+    /// As in `handle_request` with the simple syntax of the CLI
+    Request(String),
+    Show(usize),
+    Compare(usize, usize),
+    /// None shows the settings
+    Set(Option<Setting>),
+    Timing(bool),
+    Help,
+    Quit,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Setting {
+    /// `maxItems` default of both searches
+    Max(usize),
+    Format(OutputFormat),
+}
+
+impl ReplCommand {
+    /// Lines not starting with `:` are requests
+    ///
+    /// ```
+    /// use backend::library::repl::{ReplCommand, Setting};
+    ///
+    /// assert_eq!(Ok(ReplCommand::Request("paris".into())), ReplCommand::parse(" paris "));
+    /// assert_eq!(Ok(ReplCommand::Compare(1, 2)), ReplCommand::parse(":compare 1 2"));
+    /// assert_eq!(Ok(ReplCommand::Set(Some(Setting::Max(20)))), ReplCommand::parse(":set max 20"));
+    /// assert!(ReplCommand::parse(":show").is_err());
+    /// ```
+    pub fn parse(line: &str) -> Result<ReplCommand, String> {
+        let line = line.trim();
+        if !line.starts_with(':') {
+            return Ok(ReplCommand::Request(line.to_owned()));
+        }
+
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap();
+        let args = words.collect::<Vec<_>>();
+        let id = |arg: &str| arg.parse::<usize>().map_err(|_| format!("Invalid city id \"{}\"", arg));
+        let usage = || format!("Invalid arguments of {}, see :help", command);
+        match (command, args.as_slice()) {
+            (":show", [a]) => Ok(ReplCommand::Show(id(a)?)),
+            (":compare", [a, b]) => Ok(ReplCommand::Compare(id(a)?, id(b)?)),
+            (":set", []) => Ok(ReplCommand::Set(None)),
+            (":set", ["max", max]) => max.parse()
+                .map(|max| ReplCommand::Set(Some(Setting::Max(max))))
+                .map_err(|_| format!("Invalid max \"{}\"", max)),
+            (":set", ["format", format]) => OutputFormat::from_str(format, true)
+                .map(|format| ReplCommand::Set(Some(Setting::Format(format))))
+                .map_err(|_| format!("Invalid format \"{}\", expected table, jsonl or csv", format)),
+            (":timing", ["on"]) => Ok(ReplCommand::Timing(true)),
+            (":timing", ["off"]) => Ok(ReplCommand::Timing(false)),
+            (":help", []) => Ok(ReplCommand::Help),
+            (":quit" | ":q" | ":exit", []) => Ok(ReplCommand::Quit),
+            _ if COMMANDS.contains(&command) => Err(usage()),
+            _ => Err(format!("Unknown command {}, see :help", command)),
+        }
+    }
+}
+
+/// Cities side by side, as the items of a `getCities` response: their fields one per line, the distance
+/// between two cities, then the monthly climate with a column per climate variable. With several cities,
+/// each cell has their values separated by `/`.
+///
+/// ```
+/// use backend::library::repl::write_cities;
+///
+/// let city = serde_json::json!({ "id": 1, "city": { "names": ["Paris", "Lutetia"], "climate": { "tmaxMonthly": [7.5] } } });
+/// let mut out = Vec::new();
+/// write_cities(&mut out, &[city]).unwrap();
+/// assert_eq!("id     1\nnames  Paris, Lutetia\n\nmonth  tmax\nJan    7.5\n", String::from_utf8(out).unwrap());
+/// ```
+pub fn write_cities(out: &mut impl Write, items: &[Value]) -> io::Result<()> {
+    let cities = items.iter().map(|item| &item["city"]).collect::<Vec<_>>();
+    let cell = |values: Vec<&Value>| values.into_iter().map(to_text).collect::<Vec<_>>().join(" / ");
+
+    let mut rows = vec![vec!["id".to_owned(), cell(items.iter().map(|item| &item["id"]).collect())]];
+    for (key, value) in cities[0].as_object().into_iter().flatten() {
+        if !value.is_object() {
+            rows.push(vec![key.clone(), cell(cities.iter().map(|city| &city[key]).collect())]);
+        }
+    }
+    if let [a, b] = cities.as_slice() {
+        let coordinates = |city: &Value| (city["latitude"].as_f64().unwrap_or_default(), city["longitude"].as_f64().unwrap_or_default());
+        let ((a_lat, a_lon), (b_lat, b_lon)) = (coordinates(a), coordinates(b));
+        rows.push(vec!["distanceKm".to_owned(), format!("{:.0}", get_arc_distance_km(a_lat, a_lon, b_lat, b_lon))]);
+    }
+    write_columns(out, &rows)?;
+
+    let variables = cities[0]["climate"].as_object().into_iter().flatten()
+        .map(|(key, _)| key)
+        .collect::<Vec<_>>();
+    if !variables.is_empty() {
+        let mut rows = vec![std::iter::once("month")
+            .chain(variables.iter().map(|key| key.strip_suffix("Monthly").unwrap_or(key)))
+            .map(str::to_owned)
+            .collect()];
+        for (i, month) in MONTHS.iter().enumerate() {
+            let values = variables.iter()
+                .map(|key| cell(cities.iter().map(|city| &city["climate"][key][i]).collect()));
+            rows.push(std::iter::once(month.to_string()).chain(values).collect());
+        }
+        // Up to the months the variables have, e.g. after a projection
+        let months = variables.iter()
+            .filter_map(|key| cities[0]["climate"][key].as_array().map(Vec::len))
+            .max()
+            .unwrap_or_default();
+        rows.truncate(months + 1);
+        writeln!(out)?;
+        write_columns(out, &rows)?;
+    }
+    Ok(())
+}
+
+fn to_text(value: &Value) -> String {
+    match value {
+        Value::Null => "-".to_owned(),
+        Value::String(text) => text.clone(),
+        Value::Array(values) => values.iter().map(to_text).collect::<Vec<_>>().join(", "),
+        _ => value.to_string(),
+    }
+}
+
+/// Completes commands, and city names from the search index
+#[derive(Helper, Hinter, Highlighter, Validator)]
+pub struct ReplHelper {
+    data: Arc<CachedData>,
+}
+
+impl ReplHelper {
+    pub fn new(data: Arc<CachedData>) -> ReplHelper {
+        ReplHelper { data }
+    }
+}
+
+impl Completer for ReplHelper {
+    type Candidate = String;
+
+    /// The whole line is a prefix of a city name, which may have spaces
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<String>)> {
+        let prefix = &line[..pos];
+        let candidates = if prefix.starts_with(':') {
+            COMMANDS.iter()
+                .filter(|command| command.starts_with(prefix))
+                .map(|command| command.to_string())
+                .collect()
+        } else if prefix.trim().is_empty() || prefix.trim().parse::<usize>().is_ok() {
+            Vec::new()
+        } else {
+            self.data.complete_city_name(prefix.trim_start(), MAX_COMPLETIONS).into_iter()
+                .map(str::to_owned)
+                .collect()
+        };
+        Ok((0, candidates))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Ok(ReplCommand::Request("city 1 2".into())), ReplCommand::parse("city 1 2"));
+        assert_eq!(Ok(ReplCommand::Show(7)), ReplCommand::parse(":show  7"));
+        assert_eq!(Ok(ReplCommand::Set(Some(Setting::Format(OutputFormat::Csv)))), ReplCommand::parse(":set format CSV"));
+        assert_eq!(Ok(ReplCommand::Timing(false)), ReplCommand::parse(":timing off"));
+        assert_eq!(Ok(ReplCommand::Quit), ReplCommand::parse(":q"));
+        assert_eq!(Err("Invalid city id \"x\"".into()), ReplCommand::parse(":compare 1 x"));
+        assert_eq!(Err("Invalid arguments of :timing, see :help".into()), ReplCommand::parse(":timing"));
+        assert_eq!(Err("Unknown command :shw, see :help".into()), ReplCommand::parse(":shw 1"));
+    }
+
+    #[test]
+    fn test_compare() {
+        let city = |id, name, tmax: f32, humidity: Option<f32>| json!({ "id": id, "city": {
+            "names": [name], "latitude": 0.0, "longitude": id, "adminUnit": null,
+            "climate": { "humidityMonthly": vec![humidity; 12], "tmaxMonthly": vec![tmax; 12] },
+        } });
+        let mut out = Vec::new();
+        write_cities(&mut out, &[city(0, "A", 1.5, None), city(1, "B", 20.0, Some(60.0))]).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines = out.lines().collect::<Vec<_>>();
+        assert_eq!(["id          0 / 1", "names       A / B", "latitude    0.0 / 0.0", "longitude   0 / 1", "adminUnit   - / -", "distanceKm  111", ""], lines[..7]);
+        assert_eq!("month  humidity  tmax", lines[7]);
+        assert_eq!("Jan    - / 60.0  1.5 / 20.0", lines[8]);
+        assert_eq!(20, lines.len());
+    }
+}
@@ -0,0 +1,127 @@
+use crate::library::metrics;
+use lru::LruCache;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+
+pub const DEFAULT_MAX_BYTES: usize = 64 * 1024 * 1024;
+
+/// Rough overhead of an entry besides the key and the value: the LRU links and the hash table slot
+const ENTRY_OVERHEAD_BYTES: usize = 64;
+
+/// Search results by their parameters, bounded by an estimate of their memory, evicting the least recently
+/// used ones. Belongs to the search data of one dataset, so a reload starts with an empty cache.
+///
+/// ```
+/// use backend::library::result_cache::ResultCache;
+///
+/// let cache = ResultCache::new("example", 1000);
+/// assert!(cache.get(&1).is_none());
+/// cache.insert(1, "one", 400);
+/// cache.insert(2, "two", 400);
+/// assert!(cache.get(&1).is_some());
+/// // Evicts 2, which was used less recently than 1
+/// cache.insert(3, "three", 400);
+/// assert!(cache.get(&2).is_none());
+/// assert_eq!(Some("one"), cache.get(&1).as_deref().copied());
+/// ```
+pub struct ResultCache<K: Hash + Eq, V> {
+    /// `search` label of the metrics
+    search: &'static str,
+    max_bytes: usize,
+    inner: Mutex<Inner<K, V>>,
+}
+
+struct Inner<K: Hash + Eq, V> {
+    entries: LruCache<K, (Arc<V>, usize)>,
+    bytes: usize,
+}
+
+impl<K: Hash + Eq, V> ResultCache<K, V> {
+    /// `max_bytes` of 0 disables the cache
+    pub fn new(search: &'static str, max_bytes: usize) -> ResultCache<K, V> {
+        ResultCache {
+            search,
+            max_bytes,
+            inner: Mutex::new(Inner { entries: LruCache::unbounded(), bytes: 0 }),
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<Arc<V>> {
+        self.get_if(key, |_| true)
+    }
+
+    /// A value which is not `usable`, e.g. because it has fewer items than needed, counts as a miss
+    pub fn get_if(&self, key: &K, usable: impl FnOnce(&V) -> bool) -> Option<Arc<V>> {
+        if self.max_bytes == 0 {
+            return None;
+        }
+        let found = self.lock().entries.get(key)
+            .map(|(value, _)| value.clone())
+            .filter(|value| usable(value));
+        metrics::observe_result_cache(self.search, found.is_some());
+        found
+    }
+
+    /// `value_bytes` is the memory the value holds besides its `size_of`. A value larger than the whole
+    /// cache is not stored.
+    pub fn insert(&self, key: K, value: V, value_bytes: usize) -> Arc<V> {
+        let value = Arc::new(value);
+        let bytes = size_of::<K>() + size_of::<V>() + value_bytes + ENTRY_OVERHEAD_BYTES;
+        if bytes > self.max_bytes {
+            return value;
+        }
+
+        let mut inner = self.lock();
+        if let Some((_, (_, replaced_bytes))) = inner.entries.push(key, (value.clone(), bytes)) {
+            inner.bytes -= replaced_bytes;
+        }
+        inner.bytes += bytes;
+        while inner.bytes > self.max_bytes {
+            let (_, (_, evicted_bytes)) = inner.entries.pop_lru().unwrap();
+            inner.bytes -= evicted_bytes;
+        }
+        metrics::set_result_cache_size(self.search, inner.entries.len(), inner.bytes);
+        value
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner<K, V>> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bytes() {
+        let entry_bytes = |value_bytes| size_of::<u32>() + size_of::<Vec<u8>>() + value_bytes + ENTRY_OVERHEAD_BYTES;
+        let cache = ResultCache::new("test", 3 * entry_bytes(100));
+        for key in 0..3 {
+            cache.insert(key, vec![0u8; 100], 100);
+        }
+        assert_eq!(3 * entry_bytes(100), cache.lock().bytes);
+
+        // Replacing an entry accounts for the new size only
+        cache.insert(0, vec![0u8; 50], 50);
+        assert_eq!(2 * entry_bytes(100) + entry_bytes(50), cache.lock().bytes);
+        assert_eq!(3, cache.lock().entries.len());
+
+        // Evicts as many as needed
+        cache.insert(3, vec![0u8; 200], 200);
+        assert_eq!(2, cache.lock().entries.len());
+        assert!(cache.get(&1).is_none() && cache.get(&2).is_none());
+
+        // Too large for the whole cache
+        cache.insert(4, vec![0u8; 1000], 1000);
+        assert!(cache.get(&4).is_none());
+        assert!(cache.get(&3).is_some());
+    }
+
+    #[test]
+    fn test_disabled() {
+        let cache = ResultCache::new("test", 0);
+        assert_eq!(1, *cache.insert(1, 1, 0));
+        assert!(cache.get(&1).is_none());
+    }
+}
@@ -0,0 +1,227 @@
+use crate::library::{api::*, api_error::*};
+use hyper::Method;
+use serde::Deserialize;
+
+/// What an HTTP request asks for, independent of the transport details
+#[derive(Debug, PartialEq)]
+pub enum Route {
+    /// `POST /` with a JSON `CityRequest` in the body
+    Command,
+    /// `GET /cities/{id}`
+    GetCity(usize),
+    /// `GET /cities/stream`, WebSocket of `searchCity` messages, answered as you type
+    SearchStream,
+    /// `GET /cities?q=...&start=...&max=...`
+    SearchCities(CitySearchRequest),
+    /// `GET /cities/{id}/similar?start=...&max=...`
+    SimilarCities(ClimateSearchRequest),
+    /// `GET /healthz`, liveness: the process serves requests
+    Health,
+    /// `GET /readyz`, readiness: the dataset is loaded
+    Ready,
+    /// `GET /metrics`, Prometheus text format
+    Metrics,
+    /// `POST /admin/reload`, re-read the shards and swap in the new dataset
+    AdminReload,
+}
+
+impl Route {
+    /// Routes which run commands against the dataset, and so are rate limited
+    pub fn is_data(&self) -> bool {
+        matches!(self, Route::Command | Route::GetCity(_) | Route::SearchStream | Route::SearchCities(_) | Route::SimilarCities(_))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RouteError {
+    NotFound,
+    /// Contains the value for the `Allow` header
+    MethodNotAllowed(&'static str),
+    BadQuery(ApiError),
+}
+
+impl From<RouteError> for ApiError {
+    fn from(err: RouteError) -> ApiError {
+        match err {
+            RouteError::NotFound => ApiError::new(ErrorCode::NotFound, "Not found"),
+            RouteError::MethodNotAllowed(allow) => ApiError::new(ErrorCode::MethodNotAllowed, format!("Method not allowed, use {}", allow)),
+            RouteError::BadQuery(api_error) => api_error,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CitiesQuery {
+    q: String,
+    start: Option<usize>,
+    max: Option<usize>,
+    /// Comma-separated
+    fields: Option<String>,
+    autocomplete: Option<bool>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SimilarQuery {
+    start: Option<usize>,
+    max: Option<usize>,
+    fields: Option<String>,
+}
+
+/// ```
+/// use backend::library::router::{route, Route, RouteError};
+/// use hyper::Method;
+///
+/// assert_eq!(Ok(Route::Command), route(&Method::POST, "/", None));
+/// assert_eq!(Ok(Route::GetCity(42)), route(&Method::GET, "/cities/42", None));
+/// assert_eq!(Err(RouteError::NotFound), route(&Method::GET, "/cities/abc", None));
+/// assert_eq!(Err(RouteError::MethodNotAllowed("GET")), route(&Method::POST, "/cities/42", None));
+/// ```
+pub fn route(method: &Method, path: &str, query: Option<&str>) -> Result<Route, RouteError> {
+    let segments = path.trim_matches('/')
+        .split('/')
+        .filter(|it| !it.is_empty())
+        .collect::<Vec<_>>();
+
+    match segments.as_slice() {
+        [] => {
+            expect_method(method, &Method::POST, "POST")?;
+            Ok(Route::Command)
+        },
+        ["cities"] => {
+            expect_method(method, &Method::GET, "GET")?;
+            let q = parse_query::<CitiesQuery>(query)?
+                .ok_or_else(|| RouteError::BadQuery(ApiError::new(ErrorCode::BadJson, "missing field `q`").with_field("q")))?;
+            Ok(Route::SearchCities(CitySearchRequest {
+                query: q.q,
+                start_index: q.start,
+                max_items: q.max,
+                fields: q.fields.as_deref().map(split_fields),
+                autocomplete: q.autocomplete,
+            }))
+        },
+        ["cities", "stream"] => {
+            expect_method(method, &Method::GET, "GET")?;
+            Ok(Route::SearchStream)
+        },
+        ["cities", id] => {
+            let city_id = parse_id(id)?;
+            expect_method(method, &Method::GET, "GET")?;
+            Ok(Route::GetCity(city_id))
+        },
+        ["cities", id, "similar"] => {
+            let city_id = parse_id(id)?;
+            expect_method(method, &Method::GET, "GET")?;
+            let q = parse_query::<SimilarQuery>(query)?.unwrap_or_default();
+            Ok(Route::SimilarCities(ClimateSearchRequest {
+                city_id,
+                start_index: q.start,
+                max_items: q.max,
+                fields: q.fields.as_deref().map(split_fields),
+            }))
+        },
+        ["healthz"] => {
+            expect_method(method, &Method::GET, "GET")?;
+            Ok(Route::Health)
+        },
+        ["readyz"] => {
+            expect_method(method, &Method::GET, "GET")?;
+            Ok(Route::Ready)
+        },
+        ["metrics"] => {
+            expect_method(method, &Method::GET, "GET")?;
+            Ok(Route::Metrics)
+        },
+        ["admin", "reload"] => {
+            expect_method(method, &Method::POST, "POST")?;
+            Ok(Route::AdminReload)
+        },
+        _ => Err(RouteError::NotFound),
+    }
+}
+
+fn expect_method(actual: &Method, expected: &Method, allow: &'static str) -> Result<(), RouteError> {
+    if actual == expected {
+        Ok(())
+    } else {
+        Err(RouteError::MethodNotAllowed(allow))
+    }
+}
+
+fn parse_id(segment: &str) -> Result<usize, RouteError> {
+    segment.parse().map_err(|_| RouteError::NotFound)
+}
+
+fn split_fields(fields: &str) -> Vec<String> {
+    fields.split(',')
+        .map(str::trim)
+        .filter(|it| !it.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+fn parse_query<'de, T: Deserialize<'de>>(query: Option<&'de str>) -> Result<Option<T>, RouteError> {
+    match query {
+        None | Some("") => Ok(None),
+        Some(q) => serde_urlencoded::from_str(q)
+            .map(Some)
+            .map_err(|e| RouteError::BadQuery(ApiError::from_serde_message(&e.to_string(), None))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_search() {
+        let autocomplete = route(&Method::GET, "/cities", Some("q=ber&autocomplete=true")).unwrap();
+        assert!(matches!(autocomplete, Route::SearchCities(CitySearchRequest { autocomplete: Some(true), .. })));
+
+        let route = route(&Method::GET, "/cities", Some("q=paris%20texas&start=1&max=5&fields=names[0],latitude")).unwrap();
+        assert_eq!(Route::SearchCities(CitySearchRequest {
+            query: "paris texas".into(),
+            start_index: Some(1),
+            max_items: Some(5),
+            fields: Some(vec!["names[0]".into(), "latitude".into()]),
+            autocomplete: None,
+        }), route);
+    }
+
+    #[test]
+    fn test_similar() {
+        assert_eq!(
+            Ok(Route::SimilarCities(ClimateSearchRequest { city_id: 7, start_index: None, max_items: Some(3), fields: None })),
+            route(&Method::GET, "/cities/7/similar/", Some("max=3")),
+        );
+        assert_eq!(
+            Ok(Route::SimilarCities(ClimateSearchRequest { city_id: 7, start_index: None, max_items: None, fields: None })),
+            route(&Method::GET, "/cities/7/similar", None),
+        );
+    }
+
+    #[test]
+    fn test_service_routes() {
+        assert_eq!(Ok(Route::Health), route(&Method::GET, "/healthz", None));
+        assert_eq!(Ok(Route::Ready), route(&Method::GET, "/readyz/", None));
+        assert_eq!(Err(RouteError::MethodNotAllowed("GET")), route(&Method::POST, "/readyz", None));
+        assert_eq!(Ok(Route::Metrics), route(&Method::GET, "/metrics", None));
+        assert_eq!(Ok(Route::SearchStream), route(&Method::GET, "/cities/stream", None));
+        assert_eq!(Ok(Route::AdminReload), route(&Method::POST, "/admin/reload", None));
+        assert_eq!(Err(RouteError::MethodNotAllowed("POST")), route(&Method::GET, "/admin/reload", None));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(Err(RouteError::NotFound), route(&Method::GET, "/foo", None));
+        assert_eq!(Err(RouteError::NotFound), route(&Method::GET, "/cities/1/2", None));
+        assert_eq!(Err(RouteError::MethodNotAllowed("POST")), route(&Method::GET, "/", None));
+        assert!(matches!(route(&Method::GET, "/cities", None), Err(RouteError::BadQuery(_))));
+        assert!(matches!(
+            route(&Method::GET, "/cities", Some("query=x")),
+            Err(RouteError::BadQuery(ApiError { code: ErrorCode::UnknownField, .. }))
+        ));
+        assert!(matches!(route(&Method::GET, "/cities/1/similar", Some("max=-1")), Err(RouteError::BadQuery(_))));
+    }
+}
@@ -0,0 +1,358 @@
+use crate::library::{api::*, api_error::ErrorResponse, format::Format};
+use schemars::generate::SchemaSettings;
+use serde_json::{json, Map, Value};
+use std::path::PathBuf;
+
+const DEFINITIONS_PATH: &str = "/components/schemas";
+
+const GENERATED_HEADER: &str = "Generated from the Rust API types by `cargo run -p backend --bin schema`, do not edit";
+
+/// Schemas by type name: requests as they are deserialized, responses as they are serialized
+pub fn definitions() -> Map<String, Value> {
+    let settings = SchemaSettings::draft2020_12().with(|it| it.definitions_path = DEFINITIONS_PATH.into());
+
+    let mut requests = settings.clone().for_deserialize().into_generator();
+    requests.subschema_for::<CityRequest>();
+    let mut responses = settings.for_serialize().into_generator();
+    responses.subschema_for::<CityResponse>();
+    responses.subschema_for::<CitySearchStreamResponse>();
+    responses.subschema_for::<ErrorResponse>();
+
+    let mut definitions = requests.take_definitions(true);
+    definitions.extend(responses.take_definitions(true));
+    extract_titled(definitions)
+}
+
+/// Variants of a tagged enum whose struct denies unknown fields are inlined, because `$ref` can't be
+/// combined with `additionalProperties: false`. Those with a `title` become definitions of their own,
+/// which follow the enum.
+fn extract_titled(definitions: Map<String, Value>) -> Map<String, Value> {
+    let mut result = Map::new();
+    for (name, mut schema) in definitions {
+        let mut extracted = Vec::new();
+        let variants = schema.get_mut("oneOf").and_then(Value::as_array_mut).into_iter().flatten();
+        for variant in variants {
+            if let Some(Value::String(title)) = variant.as_object_mut().and_then(|it| it.remove("title")) {
+                let inline = std::mem::replace(variant, json!({ "$ref": format!("#{}/{}", DEFINITIONS_PATH, title) }));
+                extracted.push((title, inline));
+            }
+        }
+        result.insert(name, schema);
+        result.extend(extracted);
+    }
+    result
+}
+
+/// OpenAPI 3.1 document of the HTTP server
+pub fn openapi() -> Value {
+    let schema = |name: &str| json!({ "$ref": format!("#{}/{}", DEFINITIONS_PATH, name) });
+    let command_response = |name: &str, command: &str| json!({
+        "$ref": format!("#{}/{}", DEFINITIONS_PATH, name),
+        "properties": { "command": { "const": command } },
+        "required": ["command"],
+    });
+    let json_content = |schema: Value| json!({ "application/json": { "schema": schema } });
+    // Commands and their errors come in the format negotiated with `Accept`, and POST bodies are read as `Content-Type` says
+    let command_content = |schema: Value| Value::Object(
+        [Format::Json, Format::MessagePack, Format::Cbor].into_iter()
+            .map(|format| (format.content_type().to_owned(), json!({ "schema": schema })))
+            .collect()
+    );
+    let error_responses = json!({
+        "default": { "description": "Error, see `ErrorCode`", "content": json_content(schema("ErrorResponse")) },
+    });
+    let command_error_responses = json!({
+        "default": { "description": "Error, see `ErrorCode`", "content": command_content(schema("ErrorResponse")) },
+    });
+    let cached_get = |summary: &str, parameters: Value, response: Value| {
+        let mut responses = json!({
+            "200": { "description": "OK", "content": command_content(response) },
+            "304": { "description": "Not modified since the `ETag` given in `If-None-Match`, an opaque validator which changes with the dataset, the page size defaults and the negotiated format" },
+        });
+        responses.as_object_mut().unwrap().extend(command_error_responses.as_object().unwrap().clone());
+        json!({ "get": { "summary": summary, "parameters": parameters, "responses": responses } })
+    };
+    let id_param = json!({ "name": "id", "in": "path", "required": true, "schema": { "type": "integer", "minimum": 0 } });
+    let paging_params = [
+        json!({ "name": "start", "in": "query", "description": "`startIndex`", "schema": { "type": "integer", "minimum": 0 } }),
+        json!({ "name": "max", "in": "query", "description": "`maxItems`", "schema": { "type": "integer", "minimum": 0 } }),
+        json!({ "name": "fields", "in": "query", "description": "Comma-separated `fields`", "schema": { "type": "string" } }),
+    ];
+    let status = |statuses: &[&str], with_version: bool| {
+        let mut properties = json!({ "status": { "enum": statuses } });
+        if with_version {
+            properties["datasetVersion"] = json!({ "type": "string" });
+        }
+        json_content(json!({ "type": "object", "properties": properties, "required": ["status"] }))
+    };
+
+    json!({
+        "openapi": "3.1.0",
+        "info": {
+            "title": "Somewhere Like",
+            "description": "Find a place with a similar climate",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": {
+            "/": {
+                "post": {
+                    "summary": "Run a command, or a batch of commands given as an array",
+                    "requestBody": {
+                        "required": true,
+                        "content": command_content(json!({
+                            "oneOf": [schema("CityRequest"), { "type": "array", "items": schema("CityRequest") }],
+                        })),
+                    },
+                    "responses": {
+                        "200": {
+                            "description": "Response to the command, or an array of responses and errors in the order of the batch",
+                            "content": command_content(json!({
+                                "oneOf": [
+                                    schema("CityResponse"),
+                                    { "type": "array", "items": { "oneOf": [schema("CityResponse"), schema("ErrorResponse")] } },
+                                ],
+                            })),
+                        },
+                        "default": command_error_responses["default"],
+                    },
+                },
+            },
+            "/cities/{id}": cached_get("Get a city", json!([id_param]), command_response("CityGetResponse", "getCity")),
+            "/cities": cached_get(
+                "Search cities by name",
+                json!([
+                    { "name": "q", "in": "query", "required": true, "description": "`query`", "schema": { "type": "string" } },
+                    paging_params[0], paging_params[1], paging_params[2],
+                    { "name": "autocomplete", "in": "query", "description": "`autocomplete`", "schema": { "type": "boolean" } },
+                ]),
+                command_response("CitySearchResponse", "searchCity"),
+            ),
+            "/cities/stream": {
+                "get": {
+                    "summary": "Search cities as you type, over a WebSocket",
+                    "description": "Send `CitySearchRequest` text messages with `command: searchCity`. \
+                        Only the latest one is answered, with `CitySearchStreamResponse` or `ErrorResponse` messages.",
+                    "responses": {
+                        "101": { "description": "Switched to the WebSocket protocol" },
+                        "default": error_responses["default"],
+                    },
+                },
+            },
+            "/cities/{id}/similar": cached_get(
+                "Search cities with a similar climate",
+                json!([id_param, paging_params[0], paging_params[1], paging_params[2]]),
+                command_response("ClimateSearchResponse", "searchClimate"),
+            ),
+            "/healthz": {
+                "get": {
+                    "summary": "Liveness",
+                    "responses": { "200": { "description": "The process serves requests", "content": status(&["ok"], false) } },
+                },
+            },
+            "/readyz": {
+                "get": {
+                    "summary": "Readiness",
+                    "responses": {
+                        "200": { "description": "The dataset is loaded", "content": status(&["ready"], true) },
+                        "default": error_responses["default"],
+                    },
+                },
+            },
+            "/metrics": {
+                "get": {
+                    "summary": "Prometheus metrics",
+                    "responses": { "200": { "description": "Text exposition format", "content": { "text/plain": { "schema": { "type": "string" } } } } },
+                },
+            },
+            "/admin/reload": {
+                "post": {
+                    "summary": "Re-read the shards and swap in the new dataset",
+                    "security": [{ "adminToken": [] }],
+                    "responses": {
+                        "200": { "description": "Reloaded, or the shards are unchanged", "content": status(&["reloaded", "unchanged"], true) },
+                        "default": error_responses["default"],
+                    },
+                },
+            },
+        },
+        "components": {
+            "schemas": definitions(),
+            "securitySchemes": { "adminToken": { "type": "http", "scheme": "bearer" } },
+        },
+    })
+}
+
+/// TypeScript types of the definitions, in their order
+pub fn typescript() -> String {
+    let mut ts = format!("// {}\n", GENERATED_HEADER);
+    for (name, schema) in definitions() {
+        ts.push('\n');
+        ts.push_str(&ts_doc(&schema, ""));
+        let members = ts_union_members(&schema);
+        if members.len() > 1 {
+            ts.push_str(&format!("export type {} =\n", name));
+            for member in members {
+                ts.push_str(&format!("    | {}\n", ts_type(&member, "    ").0));
+            }
+        } else {
+            ts.push_str(&format!("export type {} = {}\n", name, ts_type(&schema, "").0));
+        }
+    }
+    ts
+}
+
+/// Generated files, by their path, with the current contents
+pub fn generated_files() -> Vec<(PathBuf, String)> {
+    let workspace = PathBuf::from(env!("CARGO_MANIFEST_DIR")).parent().unwrap().to_owned();
+    vec![
+        (workspace.join("backend/openapi.json"), serde_json::to_string_pretty(&openapi()).unwrap() + "\n"),
+        (workspace.join("app/api.generated.ts"), typescript()),
+    ]
+}
+
+/// Binding strength of a rendered type, to know when it needs parentheses
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+enum Precedence {
+    Union,
+    Intersection,
+    Atom,
+}
+
+/// Nested unions and enums are flattened
+fn ts_union_members(schema: &Value) -> Vec<Value> {
+    if let Some(variants) = schema.get("oneOf").or_else(|| schema.get("anyOf")).and_then(Value::as_array) {
+        return variants.iter().flat_map(ts_union_members).collect();
+    }
+    match schema.get("enum").and_then(Value::as_array) {
+        Some(values) => values.iter().map(|it| json!({ "const": it })).collect(),
+        None => vec![schema.clone()],
+    }
+}
+
+fn ts_type(schema: &Value, indent: &str) -> (String, Precedence) {
+    let union = |types: Vec<(String, Precedence)>| {
+        let mut names = Vec::<String>::new();
+        for (name, _) in types {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+        match names.len() {
+            1 => (names.pop().unwrap(), Precedence::Atom),
+            _ => (names.join(" | "), Precedence::Union),
+        }
+    };
+
+    if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+        let name = reference.rsplit('/').next().unwrap().to_owned();
+        return match schema.get("properties") {
+            Some(_) => (format!("{} & {}", name, ts_object(schema, indent)), Precedence::Intersection),
+            None => (name, Precedence::Atom),
+        };
+    }
+    if let Some(value) = schema.get("const") {
+        return (ts_literal(value), Precedence::Atom);
+    }
+    if ["oneOf", "anyOf", "enum"].iter().any(|it| schema.get(it).is_some()) {
+        return union(ts_union_members(schema).iter().map(|it| ts_type(it, indent)).collect());
+    }
+    match schema.get("type") {
+        Some(Value::Array(types)) => union(types.iter()
+            .filter_map(Value::as_str)
+            .map(|it| ts_simple_type(schema, it, indent))
+            .collect()),
+        Some(Value::String(ty)) => ts_simple_type(schema, ty, indent),
+        _ => ("unknown".into(), Precedence::Atom),
+    }
+}
+
+fn ts_simple_type(schema: &Value, ty: &str, indent: &str) -> (String, Precedence) {
+    let rendered = match ty {
+        "string" => "string".into(),
+        "integer" | "number" => "number".into(),
+        "boolean" => "boolean".into(),
+        "null" => "null".into(),
+        "array" => {
+            let (item, precedence) = schema.get("items")
+                .map(|it| ts_type(it, indent))
+                .unwrap_or_else(|| ("unknown".into(), Precedence::Atom));
+            let len = schema.get("minItems").filter(|it| *it == schema.get("maxItems").unwrap_or(&Value::Null));
+            match len.and_then(Value::as_u64) {
+                Some(len) => format!("[{}]", vec![item; len as usize].join(", ")),
+                None if precedence == Precedence::Atom => format!("{}[]", item),
+                None => format!("({})[]", item),
+            }
+        },
+        "object" if schema.get("properties").is_some() => ts_object(schema, indent),
+        "object" => "{ [key: string]: unknown }".into(),
+        _ => "unknown".into(),
+    };
+    (rendered, Precedence::Atom)
+}
+
+fn ts_object(schema: &Value, indent: &str) -> String {
+    let required = schema.get("required").and_then(Value::as_array).cloned().unwrap_or_default();
+    let inner_indent = format!("{}    ", indent);
+    let mut ts = "{\n".to_owned();
+    for (name, property) in schema["properties"].as_object().unwrap() {
+        let optional = if required.contains(&Value::String(name.clone())) { "" } else { "?" };
+        ts.push_str(&ts_doc(property, &inner_indent));
+        ts.push_str(&format!("{}{}{}: {}\n", inner_indent, name, optional, ts_type(property, &inner_indent).0));
+    }
+    ts.push_str(indent);
+    ts.push('}');
+    ts
+}
+
+fn ts_literal(value: &Value) -> String {
+    match value {
+        Value::String(s) => format!("'{}'", s.replace('\\', "\\\\").replace('\'', "\\'")),
+        other => other.to_string(),
+    }
+}
+
+fn ts_doc(schema: &Value, indent: &str) -> String {
+    match schema.get("description").and_then(Value::as_str) {
+        Some(description) if description.contains('\n') => {
+            let lines = description.lines()
+                .map(|line| format!("{} * {}\n", indent, line).replace(" * \n", " *\n"))
+                .collect::<String>();
+            format!("{}/**\n{}{} */\n", indent, lines, indent)
+        },
+        Some(description) => format!("{}/** {} */\n", indent, description),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generated_files_up_to_date() {
+        for (path, contents) in generated_files() {
+            let checked_in = std::fs::read_to_string(&path).unwrap_or_default();
+            assert!(
+                checked_in == contents,
+                "{} is stale, regenerate it with `cargo run -p backend --bin schema`",
+                path.display(),
+            );
+        }
+    }
+
+    #[test]
+    fn test_ts_type() {
+        let ts = |schema: Value| ts_type(&schema, "").0;
+        assert_eq!("(number | null)[]", ts(json!({ "type": "array", "items": { "type": ["number", "null"] } })));
+        assert_eq!("[string, string]", ts(json!({ "type": "array", "items": { "type": "string" }, "minItems": 2, "maxItems": 2 })));
+        assert_eq!("'a' | 'b' | 'c'", ts(json!({ "oneOf": [{ "enum": ["a", "b"] }, { "const": "c" }] })));
+        assert_eq!(
+            "City & {\n    /** Tag */\n    command: 'getCity'\n    extra?: string | null\n}",
+            ts(json!({
+                "$ref": "#/components/schemas/City",
+                "properties": { "command": { "const": "getCity", "description": "Tag" }, "extra": { "type": ["string", "null"] } },
+                "required": ["command"],
+            })),
+        );
+    }
+}
@@ -1,57 +1,261 @@
 use common::city::City;
-use crate::library::{api::*, intern::*, jaro::jaro_winkler_vec, split::split_name_rest};
+use crate::library::{api::*, char_index::CharIndex, deadline::Deadline, intern::*, jaro::jaro_winkler_vec, normalize::{normalize, transliterate}, result_cache::ResultCache, split::split_name_rest};
+use fst::{automaton::Str, Automaton, IntoStreamer, Streamer};
 use rayon::prelude::*;
 use std::cell::RefCell;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use thread_local::ThreadLocal;
 
 
 pub struct CitySearchData {
     search_items: Vec<CitySearchItem>,
     intern_registry: InternRegistry,
+    /// Of the names, to skip those which can't score above `MIN_SCORE`
+    char_index: CharIndex,
+    /// The most `score_city` can add for population
+    max_population_score: f32,
+    prefix_index: PrefixIndex,
+    /// By the normalized query
+    rankings: ResultCache<String, CityRanking>,
+}
+
+/// All matches of a query, best first
+#[derive(Debug)]
+struct CityRanking {
+    matches: Vec<CityMatch>,
+    cache_hit_rate_percent: f32,
+    pruned_percent: f32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct CityMatch {
+    id: u32,
+    /// Index of the matched name in `City::names`
+    name_index: u32,
+    score: f32,
+}
+
+/// Prefixes of up to this many characters have their most populous cities precomputed, since most
+/// of the index starts with them
+const TOP_PREFIX_CHARS: usize = 3;
+/// How many cities are precomputed per prefix; more of them are looked up in the FST
+const TOP_PREFIX_CITIES: usize = 100;
+
+/// The normalized and transliterated names in an FST, to find those starting with a prefix without
+/// scoring every name
+struct PrefixIndex {
+    /// Each name to the range of its cities in `postings`, as `start << 32 | len`
+    names: fst::Map<Vec<u8>>,
+    /// City id and index in `City::names`, the most populous city first within each name
+    postings: Vec<(u32, u32)>,
+    /// For short prefixes of more than `TOP_PREFIX_CITIES` cities, the ids of the most populous of them,
+    /// by population descending then id
+    top_cities: HashMap<String, Vec<u32>>,
 }
 
 #[derive(Debug)]
 struct CitySearchItem {
     /// Simply index in the cities list
     id: usize,
-    names_lowercase: Vec<InternId>,
-    admin_unit_lowercase: Option<InternId>,
-    country_lowercase: InternId,
+    /// Index in `City::names` and the normalized name, then the transliterated names which differ
+    names_normalized: Vec<(usize, InternId)>,
+    admin_unit_normalized: Option<InternId>,
+    country_normalized: InternId,
 }
 
-pub fn make_search_data(cities: &Vec<City>) -> CitySearchData {
+/// Up to `cache_bytes` of rankings are cached, see `ResultCache`
+pub fn make_search_data(cities: &Vec<City>, cache_bytes: usize) -> CitySearchData {
     let start = std::time::Instant::now();
     let intern_builder = InternBuilder::new();
-    let intern_lowercase = |s: &str| {
-        intern_builder.intern(s.to_lowercase().chars().collect())
+    let intern_normalized = |s: &str| {
+        intern_builder.intern(normalize(s).chars().collect())
     };
 
     let search_items = cities.par_iter().enumerate()
         .map(|(index, city)| {
-            let names_lowercase = city.names.iter()
-                .map(|it| intern_lowercase(it))
+            let mut names_normalized = city.names.iter()
+                .map(|it| intern_normalized(it))
+                .enumerate()
                 .collect::<Vec<_>>();
-            let admin_unit_lowercase = city.admin_unit.as_ref()
-                .map(|it| intern_lowercase(it));
-            let country_lowercase = intern_lowercase(&city.country);
+            for (name_index, name) in city.names.iter().enumerate() {
+                let transliterated = intern_builder.intern(transliterate(name).chars().collect());
+                if !names_normalized.iter().any(|(_, it)| *it == transliterated) {
+                    names_normalized.push((name_index, transliterated));
+                }
+            }
+            let admin_unit_normalized = city.admin_unit.as_ref()
+                .map(|it| intern_normalized(it));
+            let country_normalized = intern_normalized(&city.country);
             CitySearchItem {
                 id: index,
-                names_lowercase,
-                admin_unit_lowercase,
-                country_lowercase,
+                names_normalized,
+                admin_unit_normalized,
+                country_normalized,
             }
         })
-        .collect();
-    eprintln!("Built search items in {} ms", start.elapsed().as_millis());
+        .collect::<Vec<_>>();
+    tracing::info!(elapsed_ms = start.elapsed().as_millis() as u64, "Built search items");
+
+    let start = std::time::Instant::now();
+    let intern_registry = intern_builder.build();
+    let prefix_index = make_prefix_index(cities, &search_items, &intern_registry);
+    tracing::info!(elapsed_ms = start.elapsed().as_millis() as u64, names = prefix_index.names.len(), "Built prefix index");
+
+    let start = std::time::Instant::now();
+    let name_ids = search_items.iter().flat_map(|item| item.names_normalized.iter().map(|(_, name)| *name));
+    let char_index = CharIndex::new(&intern_registry, name_ids);
+    tracing::info!(elapsed_ms = start.elapsed().as_millis() as u64, names = char_index.len(), "Built character index");
+    let max_population = cities.iter().map(|city| city.population).max().unwrap_or_default();
 
     CitySearchData {
-        intern_registry: intern_builder.build(),
-        search_items
+        intern_registry,
+        search_items,
+        char_index,
+        max_population_score: POPULATION_LOG_WEIGHT * (max_population as f32).log10(),
+        prefix_index,
+        rankings: ResultCache::new("city", cache_bytes),
+    }
+}
+
+fn make_prefix_index(cities: &[City], search_items: &[CitySearchItem], intern_registry: &InternRegistry) -> PrefixIndex {
+    let mut entries = search_items.iter()
+        .flat_map(|item| item.names_normalized.iter().map(|(name_index, name)| (*name, item.id as u32, *name_index as u32)))
+        .collect::<Vec<_>>();
+    // By name, which sorts as its UTF-8 bytes in the FST
+    entries.par_sort_unstable_by(|a, b| intern_registry.resolve(a.0).cmp(&intern_registry.resolve(b.0))
+        .then_with(|| cities[b.1 as usize].population.cmp(&cities[a.1 as usize].population))
+        .then_with(|| (a.1, a.2).cmp(&(b.1, b.2))));
+    // Names of a city which only differ in case or diacritics, with the first index
+    entries.dedup_by_key(|(name, id, _)| (*name, *id));
+
+    let mut builder = fst::MapBuilder::memory();
+    let mut postings = Vec::with_capacity(entries.len());
+    for group in entries.chunk_by(|a, b| a.0 == b.0) {
+        let name = intern_registry.resolve(group[0].0).unwrap().iter().collect::<String>();
+        let start = postings.len() as u64;
+        postings.extend(group.iter().map(|(_, id, name_index)| (*id, *name_index)));
+        builder.insert(name, start << 32 | group.len() as u64).unwrap();
+    }
+
+    // Names with the same prefix are adjacent, as they are sorted
+    let by_population = |id: &u32| (Reverse(cities[*id as usize].population), *id);
+    let mut top_cities = HashMap::new();
+    for prefix_chars in 1..=TOP_PREFIX_CHARS {
+        let prefix = |name: InternId| &intern_registry.resolve(name).unwrap()[..prefix_chars];
+        let long_enough = entries.iter()
+            .filter(|(name, _, _)| intern_registry.resolve(*name).unwrap().len() >= prefix_chars)
+            .collect::<Vec<_>>();
+        for group in long_enough.chunk_by(|a, b| prefix(a.0) == prefix(b.0)) {
+            let mut ids = group.iter().map(|(_, id, _)| *id).collect::<Vec<_>>();
+            ids.sort_unstable_by_key(by_population);
+            ids.dedup();
+            if ids.len() > TOP_PREFIX_CITIES {
+                ids.truncate(TOP_PREFIX_CITIES);
+                top_cities.insert(prefix(group[0].0).iter().collect::<String>(), ids);
+            }
+        }
+    }
+    PrefixIndex { names: builder.into_map(), postings, top_cities }
+}
+
+impl PrefixIndex {
+    fn postings(&self, range: u64) -> &[(u32, u32)] {
+        let start = (range >> 32) as usize;
+        &self.postings[start..start + (range & u64::from(u32::MAX)) as usize]
+    }
+
+    /// The `max_items` most populous cities with a name starting with one of `prefixes`, each once with
+    /// its first such name, the most populous first and then by id
+    fn find(&self, cities: &[City], search_items: &[CitySearchItem], intern_registry: &InternRegistry, prefixes: &[String], max_items: usize) -> Vec<(u32, u32)> {
+        let mut ids = prefixes.iter()
+            .flat_map(|prefix| self.find_ids(cities, prefix, max_items))
+            .collect::<Vec<_>>();
+        ids.sort_unstable_by_key(|id| (Reverse(cities[*id as usize].population), *id));
+        ids.dedup();
+        ids.truncate(max_items);
+
+        let prefixes = prefixes.iter().map(|it| it.chars().collect::<Vec<_>>()).collect::<Vec<_>>();
+        ids.into_iter()
+            .map(|id| {
+                let name_index = search_items[id as usize].names_normalized.iter()
+                    .filter(|(_, name)| {
+                        let name = intern_registry.resolve(*name).unwrap();
+                        prefixes.iter().any(|prefix| name.starts_with(prefix))
+                    })
+                    .map(|(name_index, _)| *name_index as u32)
+                    .min()
+                    .unwrap_or_default();
+                (id, name_index)
+            })
+            .collect()
+    }
+
+    /// The ids of the `max_items` most populous cities with a name starting with `prefix`, by population
+    /// descending then id. Precomputed for short prefixes; otherwise each name under the prefix is visited,
+    /// though only its cities more populous than those found so far.
+    fn find_ids(&self, cities: &[City], prefix: &str, max_items: usize) -> Vec<u32> {
+        let top_cities = self.top_cities.get(prefix)
+            .filter(|_| max_items <= TOP_PREFIX_CITIES && prefix.chars().count() <= TOP_PREFIX_CHARS);
+        if let Some(top_cities) = top_cities {
+            return top_cities[..max_items].to_vec();
+        }
+        if max_items == 0 {
+            return Vec::new();
+        }
+        // The worst of the best cities on top
+        let mut best = BinaryHeap::new();
+        let mut found = HashSet::new();
+        let mut stream = self.names.search(Str::new(prefix).starts_with()).into_stream();
+        while let Some((_, range)) = stream.next() {
+            for &(id, _) in self.postings(range) {
+                let key = (cities[id as usize].population, Reverse(id));
+                if found.contains(&id) {
+                    continue;
+                }
+                if best.len() == max_items {
+                    let Reverse(worst) = *best.peek().unwrap();
+                    // So are the rest of the name's cities
+                    if key <= worst {
+                        break;
+                    }
+                    best.pop();
+                    found.remove(&worst.1.0);
+                }
+                best.push(Reverse(key));
+                found.insert(id);
+            }
+        }
+        best.into_sorted_vec().into_iter()
+            .map(|Reverse((_, Reverse(id)))| id)
+            .collect()
+    }
+
+    /// The names starting with `prefix` of the `max_items` most populous cities, as the city id and
+    /// index in `City::names`, with a city for each name
+    fn find_names(&self, cities: &[City], prefix: &str, max_items: usize) -> Vec<(u32, u32)> {
+        let mut best = BinaryHeap::new();
+        let mut stream = self.names.search(Str::new(prefix).starts_with()).into_stream();
+        while let Some((_, range)) = stream.next() {
+            let (id, name_index) = self.postings(range)[0];
+            best.push(Reverse((cities[id as usize].population, Reverse(id), name_index)));
+            if best.len() > max_items {
+                best.pop();
+            }
+        }
+        best.into_sorted_vec().into_iter()
+            .map(|Reverse((_, Reverse(id), name_index))| (id, name_index))
+            .collect()
     }
 }
 
 pub struct CitySearchQuery {
+    /// Normalized and trimmed query, the key of cached rankings
+    key: String,
+    /// Normalized and transliterated query, without leading spaces, for prefix matching
+    prefixes: Vec<String>,
     name_rest_variants: Vec<(InternId, Option<InternId>)>,
     intern_registry: InternRegistry,
     cache: ThreadLocal<RefCell<Vec<f32>>>,
@@ -60,16 +264,33 @@ pub struct CitySearchQuery {
     cache_hit_miss_count: (AtomicUsize, AtomicUsize),
 }
 
+/// The query is matched as typed and transliterated, so that either script finds names in the other
 pub fn make_search_query(query: &str) -> CitySearchQuery {
-    let lowercase_query = query.trim().to_lowercase();
+    let normalized_query = normalize(query).trim().to_owned();
+    let transliterated_query = transliterate(query).trim().to_owned();
+    let mut prefixes = Vec::new();
+    for prefix in [normalize(query), transliterate(query)] {
+        let prefix = prefix.trim_start().to_owned();
+        if !prefix.is_empty() && !prefixes.contains(&prefix) {
+            prefixes.push(prefix);
+        }
+    }
     let intern_builder = InternBuilder::new();
-    let name_rest_variants = split_name_rest(&lowercase_query).iter()
-        .map(|(name, rest)| (
-            intern_builder.intern(name.chars().collect()),
-            rest.map(|r| intern_builder.intern(r.chars().collect()))
-        ))
-        .collect();
+    let mut name_rest_variants = Vec::new();
+    for variant in [&normalized_query, &transliterated_query] {
+        for (name, rest) in split_name_rest(variant) {
+            let name_rest = (
+                intern_builder.intern(name.chars().collect()),
+                rest.map(|r| intern_builder.intern(r.chars().collect()))
+            );
+            if !name_rest_variants.contains(&name_rest) {
+                name_rest_variants.push(name_rest);
+            }
+        }
+    }
     CitySearchQuery {
+        key: normalized_query,
+        prefixes,
         name_rest_variants,
         intern_registry: intern_builder.build(),
         cache: ThreadLocal::new(),
@@ -77,51 +298,226 @@ pub fn make_search_query(query: &str) -> CitySearchQuery {
     }
 }
 
+/// Names starting with `prefix`, ignoring case and diacritics, those of the most populous cities first,
+/// each name once
+pub fn complete_city_names<'a>(cities: &'a [City], search_data: &CitySearchData, prefix: &str, max_items: usize) -> Vec<&'a str> {
+    let mut names = Vec::new();
+    for (id, name_index) in search_data.prefix_index.find_names(cities, &normalize(prefix), max_items) {
+        // A name and its transliteration are different index names
+        let name = cities[id as usize].names[name_index as usize].as_str();
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    names
+}
+
+
+/// When `deadline` expires, the remaining cities are skipped and the response is `truncated`.
+/// Complete rankings are cached, so another page of the same query is not scored again.
+pub fn search_cities<'a>(cities: &'a Vec<City>, search_data: &'a CitySearchData, search_query: &CitySearchQuery, start_index: usize, max_items: usize, deadline: &Deadline) -> CitySearchResponse<'a> {
+    search_cities_progressive(cities, search_data, search_query, start_index, max_items, deadline, 1, |_| {})
+}
 
-pub fn search_cities<'a>(cities: &'a Vec<City>, search_data: &'a CitySearchData, search_query: &CitySearchQuery, start_index: usize, max_items: usize) -> CitySearchResponse<'a> {
+/// Same as `search_cities`, but scans the cities in `chunks` consecutive parts, each in parallel.
+/// After each part but the last, `on_partial` gets the best items found so far.
+#[allow(clippy::too_many_arguments)]
+pub fn search_cities_progressive<'a>(
+    cities: &'a Vec<City>,
+    search_data: &'a CitySearchData,
+    search_query: &CitySearchQuery,
+    start_index: usize,
+    max_items: usize,
+    deadline: &Deadline,
+    chunks: usize,
+    mut on_partial: impl FnMut(CitySearchResponse<'a>),
+) -> CitySearchResponse<'a> {
     let started = std::time::Instant::now();
-    let mut items = search_data.search_items
-        .par_iter()
-        .map(
-            |item| {
-                score_city(&cities[item.id], item, &search_data.intern_registry, search_query, &search_query.cache, &search_query.cache_hit_miss_count)
-            }
-        )
-        .filter(|item| item.score > 0.85)
+    let (ranking, truncated) = rank_cities(cities, search_data, search_query, deadline, chunks, |ranking| {
+        on_partial(make_response(cities, ranking, start_index, max_items, started, false))
+    });
+    make_response(cities, &ranking, start_index, max_items, started, truncated)
+}
+
+/// For a search box: the cities with a name starting with the query, the most populous first. For queries
+/// of up to `TOP_PREFIX_CHARS` characters they are precomputed, and longer ones have few enough names to
+/// visit them in the FST. Only if there are fewer than `start_index + max_items` of them, they are followed
+/// by the best matches of `search_cities` which don't start with the query.
+pub fn autocomplete_cities<'a>(
+    cities: &'a [City],
+    search_data: &'a CitySearchData,
+    search_query: &CitySearchQuery,
+    start_index: usize,
+    max_items: usize,
+    deadline: &Deadline,
+) -> CitySearchResponse<'a> {
+    let started = std::time::Instant::now();
+    let wanted = start_index.saturating_add(max_items);
+    let mut matches = search_data.prefix_index.find(cities, &search_data.search_items, &search_data.intern_registry, &search_query.prefixes, wanted).into_iter()
+        .map(|(id, name_index)| CityMatch {
+            id,
+            name_index,
+            score: score_prefix_match(&cities[id as usize], &search_data.search_items[id as usize], name_index, &search_data.intern_registry, search_query),
+        })
+        .collect::<Vec<_>>();
+    // NaN as nothing was looked up in the Jaro-Winkler cache or pruned
+    let (mut cache_hit_rate_percent, mut pruned_percent, mut truncated) = (f32::NAN, f32::NAN, false);
+    if matches.len() < wanted {
+        let (ranking, ranking_truncated) = rank_cities(cities, search_data, search_query, deadline, 1, |_| {});
+        let found = matches.iter().map(|it| it.id).collect::<HashSet<_>>();
+        let missing = wanted - matches.len();
+        matches.extend(ranking.matches.iter().filter(|it| !found.contains(&it.id)).take(missing));
+        cache_hit_rate_percent = ranking.cache_hit_rate_percent;
+        pruned_percent = ranking.pruned_percent;
+        truncated = ranking_truncated;
+    }
+    make_response(cities, &CityRanking { matches, cache_hit_rate_percent, pruned_percent }, start_index, max_items, started, truncated)
+}
+
+/// The cached ranking of the query, or a new one, which is cached unless `deadline` truncated it
+fn rank_cities(
+    cities: &[City],
+    search_data: &CitySearchData,
+    search_query: &CitySearchQuery,
+    deadline: &Deadline,
+    chunks: usize,
+    mut on_partial: impl FnMut(&CityRanking),
+) -> (Arc<CityRanking>, bool) {
+    if let Some(ranking) = search_data.rankings.get(&search_query.key) {
+        return (ranking, false);
+    }
+    let (candidates, pruned_percent) = find_candidates(search_data, search_query);
+
+    let chunk_len = search_data.search_items.len().div_ceil(chunks.max(1)).max(1);
+    let chunk_count = search_data.search_items.len().div_ceil(chunk_len);
+
+    // Set once an item is skipped, as the deadline may expire after the last one was scored
+    let truncated = AtomicBool::new(false);
+    let mut matches = Vec::new();
+    for (chunk_index, chunk) in search_data.search_items.chunks(chunk_len).enumerate() {
+        matches.par_extend(chunk
+            .par_iter()
+            .filter(|_| {
+                let expired = deadline.is_expired();
+                if expired {
+                    truncated.store(true, Ordering::Relaxed);
+                }
+                !expired
+            })
+            .filter_map(
+                |item| {
+                    score_city(&cities[item.id], item, &search_data.intern_registry, search_query, &candidates, &search_query.cache, &search_query.cache_hit_miss_count)
+                }
+            )
+            .filter(|item| item.score > MIN_SCORE)
+        );
+        if chunk_index + 1 < chunk_count && !deadline.is_expired() {
+            on_partial(&make_ranking(matches.clone(), search_query, pruned_percent));
+        }
+    }
+
+    let ranking = make_ranking(matches, search_query, pruned_percent);
+    if truncated.into_inner() {
+        return (Arc::new(ranking), true);
+    }
+    let bytes = search_query.key.capacity() + ranking.matches.capacity() * size_of::<CityMatch>();
+    (search_data.rankings.insert(search_query.key.clone(), ranking, bytes), false)
+}
+
+/// For each of `name_rest_variants`, by name id, whether the names may score above `MIN_SCORE` with it,
+/// see `CharIndex`; and the percentage of indexed names which may not
+fn find_candidates(search_data: &CitySearchData, search_query: &CitySearchQuery) -> (Vec<Vec<bool>>, f32) {
+    let candidates = search_query.name_rest_variants.par_iter()
+        .map(|(name, rest)| {
+            // All but the name similarity, at most; the name position only subtracts
+            let max_rest_score = search_data.max_population_score
+                + if rest.is_some() { ADMIN_UNIT_WEIGHT + COUNTRY_WEIGHT } else { 0.0 };
+            let name = search_query.intern_registry.resolve(*name).unwrap();
+            search_data.char_index.candidates(&search_data.intern_registry, name, MIN_SCORE - max_rest_score - SCORE_ROUNDING_MARGIN)
+        })
         .collect::<Vec<_>>();
+    let kept = candidates.iter()
+        .map(|it| it.iter().filter(|it| **it).count().min(search_data.char_index.len()))
+        .sum::<usize>();
+    let total = candidates.len() * search_data.char_index.len();
+    (candidates, 100.0 * (total - kept) as f32 / total as f32)
+}
 
+fn make_ranking(mut matches: Vec<CityMatch>, search_query: &CitySearchQuery, pruned_percent: f32) -> CityRanking {
     let hit = search_query.cache_hit_miss_count.0.load(Ordering::Relaxed);
     let miss = search_query.cache_hit_miss_count.1.load(Ordering::Relaxed);
 
-    items.sort_by(|a, b| b.score.total_cmp(&a.score));
+    matches.sort_by(|a, b| b.score.total_cmp(&a.score));
+    matches.shrink_to_fit();
+    CityRanking {
+        matches,
+        cache_hit_rate_percent: 100.0 * (hit as f32 / (hit + miss) as f32),
+        pruned_percent,
+    }
+}
+
+fn make_response<'a>(
+    cities: &'a [City],
+    ranking: &CityRanking,
+    start_index: usize,
+    max_items: usize,
+    started: std::time::Instant,
+    truncated: bool,
+) -> CitySearchResponse<'a> {
+    let items = ranking.matches.iter()
+        .skip(start_index)
+        .take(max_items)
+        .map(|it| {
+            let city = &cities[it.id as usize];
+            CitySearchResponseItem {
+                id: it.id as usize,
+                score: it.score,
+                matched_name: &city.names[it.name_index as usize],
+                name: &city.names[0],
+                population: city.population,
+                admin_unit: &city.admin_unit,
+                country: &city.country,
+                city: None,
+            }
+        })
+        .collect::<Vec<_>>();
     CitySearchResponse {
-        items: items.into_iter().skip(start_index).take(max_items).collect::<Vec<_>>(),
+        items,
         elapsed_ms: started.elapsed().as_millis() as u32,
-        cache_hit_rate_percent: 100.0 * (hit as f32 / (hit + miss) as f32),
+        cache_hit_rate_percent: ranking.cache_hit_rate_percent,
+        pruned_percent: ranking.pruned_percent,
+        truncated,
     }
 }
 
+/// Matches scoring at most this are left out
+const MIN_SCORE: f32 = 0.85;
+/// Subtracted from the name similarity a candidate needs, for the rounding of the score sum
+const SCORE_ROUNDING_MARGIN: f32 = 0.001;
 const NAME_POSITION_WEIGHT: f32 = -0.001;
 const POPULATION_LOG_WEIGHT: f32 = 0.01;
 const ADMIN_UNIT_WEIGHT: f32 = 0.25;
 const COUNTRY_WEIGHT: f32 = 0.25;
 
-fn score_city<'a>(
-    city: &'a City,
-    search_item: &'a CitySearchItem,
+/// None if `candidates` rules out all of the city's names for every variant of the query
+fn score_city(
+    city: &City,
+    search_item: &CitySearchItem,
     city_intern_registry: &InternRegistry,
     city_search_query: &CitySearchQuery,
+    candidates: &[Vec<bool>],
     cache: &ThreadLocal<RefCell<Vec<f32>>>,
     cache_hit_miss_count: &(AtomicUsize, AtomicUsize),
-) -> CitySearchResponseItem<'a> {
-    city_search_query.name_rest_variants.iter()
-        .flat_map(|query_name_and_rest| {
-            search_item.names_lowercase.iter().enumerate()
-                .map(|city_name_index_and_name| {
+) -> Option<CityMatch> {
+    city_search_query.name_rest_variants.iter().zip(candidates)
+        .flat_map(|(query_name_and_rest, candidates)| {
+            search_item.names_normalized.iter()
+                .filter(|(_, name)| candidates[*name])
+                .map(|(name_index, name)| {
                     let score = score_city_impl(
-                        city_name_index_and_name,
-                        &search_item.admin_unit_lowercase,
-                        &search_item.country_lowercase,
+                        (*name_index, name),
+                        &search_item.admin_unit_normalized,
+                        &search_item.country_normalized,
                         city.population,
                         query_name_and_rest,
                         cache,
@@ -129,19 +525,28 @@ fn score_city<'a>(
                         city_intern_registry,
                         &city_search_query.intern_registry
                     );
-                    CitySearchResponseItem {
-                        id: search_item.id,
+                    CityMatch {
+                        id: search_item.id as u32,
+                        name_index: *name_index as u32,
                         score,
-                        matched_name: &city.names[city_name_index_and_name.0],
-                        name: &city.names[0],
-                        population: city.population,
-                        admin_unit: &city.admin_unit,
-                        country: &city.country
                     }
                 })
         })
         .max_by(|a, b| a.score.total_cmp(&b.score))
-        .unwrap()
+}
+
+/// As `score_city` by the name only, and without the cache, which is too large to set up for a few names
+fn score_prefix_match(city: &City, search_item: &CitySearchItem, name_index: u32, intern_registry: &InternRegistry, search_query: &CitySearchQuery) -> f32 {
+    let name_similarity = search_item.names_normalized.iter()
+        .filter(|(index, _)| *index == name_index as usize)
+        .flat_map(|(_, name)| {
+            let name = intern_registry.resolve(*name).unwrap();
+            search_query.prefixes.iter().map(|prefix| jaro_winkler_vec(name, &prefix.chars().collect::<Vec<_>>()))
+        })
+        .fold(0.0, f32::max);
+    name_similarity
+        + NAME_POSITION_WEIGHT * name_index as f32
+        + POPULATION_LOG_WEIGHT * (city.population as f32).log10()
 }
 
 fn score_city_impl(
@@ -0,0 +1,464 @@
+use crate::library::{api::*, deadline::Deadline, format::Format, handle_request::RequestOptions, result_cache};
+use clap::Parser;
+use std::{fs, hash::{DefaultHasher, Hash, Hasher}, net::{IpAddr, SocketAddr}, time::Duration};
+
+pub const CONFIG_FILE_ENV: &str = "SOMEWHERE_LIKE_CONFIG";
+const ENV_PREFIX: &str = "SOMEWHERE_LIKE_";
+
+/// Defines the keys accepted in the config file (as is), in the environment (uppercase with
+/// `SOMEWHERE_LIKE_` prefix), and as flags (with dashes instead of underscores), in `KEYS` and `Flags`
+macro_rules! keys {
+    ($($(#[doc = $doc:literal])* $key:ident,)*) => {
+        const KEYS: &[&str] = &[$(stringify!($key)),*];
+
+        /// HTTP server for the city searches. Settings can also be given in a TOML file and in
+        /// `SOMEWHERE_LIKE_*` environment variables, which flags override.
+        #[derive(Parser)]
+        #[command(name = "http")]
+        struct Flags {
+            /// TOML config file, instead of SOMEWHERE_LIKE_CONFIG
+            #[arg(long, value_name = "PATH")]
+            config: Option<String>,
+            $(
+                $(#[doc = $doc])*
+                #[arg(long, value_name = "VALUE")]
+                $key: Option<String>,
+            )*
+        }
+
+        impl Flags {
+            /// The given flags as `(key, value)`, for `ServerConfig::set`
+            fn values(self) -> Vec<(&'static str, String)> {
+                let mut values = Vec::new();
+                $(
+                    if let Some(value) = self.$key {
+                        values.push((stringify!($key), value));
+                    }
+                )*
+                values
+            }
+        }
+    };
+}
+
+keys! {
+    /// IP to listen on
+    host,
+    port,
+    /// Allowed origins, comma-separated; * allows any
+    cors_origins,
+    max_body_bytes,
+    /// "auto" means the number of CPU cores
+    worker_threads,
+    /// Default maxItems of searchCity
+    search_page_size,
+    /// Default maxItems of searchClimate
+    climate_page_size,
+    cache_max_age_secs,
+    shutdown_timeout_secs,
+    /// Bearer token for /admin/* routes
+    admin_token,
+    /// 0 disables reloading the shards
+    reload_poll_secs,
+    compression_min_bytes,
+    tls_cert_path,
+    tls_key_path,
+    keep_alive,
+    h2_keep_alive_interval_secs,
+    h2_keep_alive_timeout_secs,
+    /// 0 disables
+    search_timeout_ms,
+    /// "auto" means the number of CPU cores
+    search_concurrency,
+    max_query_bytes,
+    /// 0 disables
+    max_items_limit,
+    /// 0 disables
+    start_index_limit,
+    /// 0 disables
+    max_batch_len,
+    /// Per client IP; 0 disables
+    rate_limit_per_sec,
+    rate_limit_burst,
+    /// Proxy IPs whose forwarding headers are believed, comma-separated
+    trusted_proxies,
+    /// 0 disables
+    max_concurrent_searches,
+    /// 0 disables
+    result_cache_bytes,
+}
+
+/// HTTP server settings. Sources, from the lowest precedence to the highest:
+///
+/// * built-in defaults
+/// * TOML file given with `--config` or `SOMEWHERE_LIKE_CONFIG`
+/// * environment, e.g. `SOMEWHERE_LIKE_PORT=8080`
+/// * command-line flags, e.g. `--port 8080` or `--port=8080`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerConfig {
+    pub host: IpAddr,
+    pub port: u16,
+    /// `*` allows any origin, empty list disables CORS headers
+    pub cors_origins: Vec<String>,
+    pub max_body_bytes: usize,
+    /// None means the number of CPU cores
+    pub worker_threads: Option<usize>,
+    pub search_page_size: usize,
+    pub climate_page_size: usize,
+    /// `max-age` of `Cache-Control` for GET responses
+    pub cache_max_age_secs: u64,
+    /// How long to wait for in-flight connections on SIGTERM/SIGINT
+    pub shutdown_timeout_secs: u64,
+    /// Bearer token for `/admin/*` routes; None disables them
+    pub admin_token: Option<String>,
+    /// How often to check the shards for changes and reload them; 0 disables
+    pub reload_poll_secs: u64,
+    /// Smaller responses are sent uncompressed
+    pub compression_min_bytes: usize,
+    /// PEM files; if both are set, the server speaks TLS and negotiates HTTP/2 with ALPN
+    pub tls_cert_path: Option<String>,
+    pub tls_key_path: Option<String>,
+    /// HTTP/1.1 persistent connections
+    pub keep_alive: bool,
+    /// HTTP/2 PING interval, 0 disables
+    pub h2_keep_alive_interval_secs: u64,
+    /// Close an HTTP/2 connection if a PING is not answered within this time
+    pub h2_keep_alive_timeout_secs: u64,
+    /// Time budget of a request, including the wait for a free search slot; 0 disables
+    pub search_timeout_ms: u64,
+    /// How many requests are handled in the blocking pool at once; None means the number of CPU cores
+    pub search_concurrency: Option<usize>,
+    /// Longer query strings are rejected with 413
+    pub max_query_bytes: usize,
+    /// Largest `maxItems` a request may ask for; 0 disables
+    pub max_items_limit: usize,
+    /// Largest `startIndex` a request may ask for; 0 disables
+    pub start_index_limit: usize,
+    /// Most sub-requests of a batch and ids of `getCities`; 0 disables
+    pub max_batch_len: usize,
+    /// Requests per second per client IP (per /64 for IPv6), refilling a bucket of `rate_limit_burst`; 0 disables
+    pub rate_limit_per_sec: f64,
+    pub rate_limit_burst: u32,
+    /// Peers whose `Forwarded`/`X-Forwarded-For` headers are believed, to rate limit the client behind them
+    pub trusted_proxies: Vec<IpAddr>,
+    /// Requests handled or waiting for a search slot at once, beyond which they are rejected with 429; 0 disables
+    pub max_concurrent_searches: usize,
+    /// Memory for cached results of each of the two searches, evicting the least recently used; 0 disables
+    pub result_cache_bytes: usize,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            host: IpAddr::from([127, 0, 0, 1]),
+            port: 3001,
+            cors_origins: vec!["*".into()],
+            max_body_bytes: 64 * 1024,
+            worker_threads: None,
+            search_page_size: SEARCH_DEFAULT_MAX_ITEMS,
+            climate_page_size: CLIMATE_DEFAULT_MAX_ITEMS,
+            cache_max_age_secs: 3600,
+            shutdown_timeout_secs: 30,
+            admin_token: None,
+            reload_poll_secs: 10,
+            compression_min_bytes: 1024,
+            tls_cert_path: None,
+            tls_key_path: None,
+            keep_alive: true,
+            h2_keep_alive_interval_secs: 30,
+            h2_keep_alive_timeout_secs: 20,
+            search_timeout_ms: 5000,
+            search_concurrency: None,
+            max_query_bytes: 2048,
+            max_items_limit: 1000,
+            start_index_limit: 10_000,
+            max_batch_len: 100,
+            rate_limit_per_sec: 0.0,
+            rate_limit_burst: 40,
+            trusted_proxies: Vec::new(),
+            max_concurrent_searches: 256,
+            result_cache_bytes: result_cache::DEFAULT_MAX_BYTES,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Exits with status 2 on invalid flags, and prints the help for `--help`
+    pub fn load() -> Result<ServerConfig, String> {
+        Self::load_flags(Flags::parse(), |name| std::env::var(name).ok())
+    }
+
+    pub fn load_from(args: &[String], env: impl Fn(&str) -> Option<String>) -> Result<ServerConfig, String> {
+        let flags = Flags::try_parse_from(std::iter::once("http").chain(args.iter().map(String::as_str)))
+            .map_err(|e| e.to_string())?;
+        Self::load_flags(flags, env)
+    }
+
+    fn load_flags(flags: Flags, env: impl Fn(&str) -> Option<String>) -> Result<ServerConfig, String> {
+        let mut config = ServerConfig::default();
+
+        let config_file = flags.config.clone().or_else(|| env(CONFIG_FILE_ENV));
+        if let Some(path) = config_file {
+            let text = fs::read_to_string(&path)
+                .map_err(|e| format!("Cannot read config file \"{}\": {}", path, e))?;
+            config.apply_toml(&text)
+                .map_err(|e| format!("Config file \"{}\": {}", path, e))?;
+        }
+
+        for key in KEYS.iter().copied() {
+            let env_name = format!("{}{}", ENV_PREFIX, key.to_uppercase());
+            if let Some(value) = env(&env_name) {
+                config.set(key, &value).map_err(|e| format!("{}: {}", env_name, e))?;
+            }
+        }
+
+        for (key, value) in flags.values() {
+            config.set(key, &value).map_err(|e| format!("--{}: {}", key.replace('_', "-"), e))?;
+        }
+
+        if config.tls_cert_path.is_some() != config.tls_key_path.is_some() {
+            return Err("tls_cert_path and tls_key_path must be set together".into());
+        }
+        if config.search_concurrency == Some(0) {
+            return Err("search_concurrency must be positive".into());
+        }
+        if !(config.rate_limit_per_sec >= 0.0 && config.rate_limit_per_sec.is_finite()) {
+            return Err("rate_limit_per_sec must be a non-negative number".into());
+        }
+
+        Ok(config)
+    }
+
+    fn apply_toml(&mut self, text: &str) -> Result<(), String> {
+        let table = text.parse::<toml::Table>().map_err(|e| e.to_string())?;
+        for (key, value) in table {
+            let value_str = match value {
+                toml::Value::String(s) => s,
+                toml::Value::Array(items) => items.iter()
+                    .map(|it| it.as_str().map(str::to_owned).unwrap_or_else(|| it.to_string()))
+                    .collect::<Vec<_>>()
+                    .join(","),
+                other => other.to_string(),
+            };
+            self.set(&key, &value_str).map_err(|e| format!("{}: {}", key, e))?;
+        }
+        Ok(())
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        macro_rules! parse {
+            () => { value.trim().parse().map_err(|e| format!("invalid value \"{}\": {}", value, e))? };
+        }
+        match key {
+            "host" => self.host = parse!(),
+            "port" => self.port = parse!(),
+            "cors_origins" => self.cors_origins = value.split(',')
+                .map(str::trim)
+                .filter(|it| !it.is_empty())
+                .map(str::to_owned)
+                .collect(),
+            "max_body_bytes" => self.max_body_bytes = parse!(),
+            "worker_threads" => self.worker_threads = match value.trim() {
+                "" | "auto" => None,
+                _ => Some(parse!()),
+            },
+            "search_page_size" => self.search_page_size = parse!(),
+            "climate_page_size" => self.climate_page_size = parse!(),
+            "cache_max_age_secs" => self.cache_max_age_secs = parse!(),
+            "shutdown_timeout_secs" => self.shutdown_timeout_secs = parse!(),
+            "admin_token" => self.admin_token = optional(value),
+            "reload_poll_secs" => self.reload_poll_secs = parse!(),
+            "compression_min_bytes" => self.compression_min_bytes = parse!(),
+            "tls_cert_path" => self.tls_cert_path = optional(value),
+            "tls_key_path" => self.tls_key_path = optional(value),
+            "keep_alive" => self.keep_alive = parse!(),
+            "h2_keep_alive_interval_secs" => self.h2_keep_alive_interval_secs = parse!(),
+            "h2_keep_alive_timeout_secs" => self.h2_keep_alive_timeout_secs = parse!(),
+            "search_timeout_ms" => self.search_timeout_ms = parse!(),
+            "search_concurrency" => self.search_concurrency = match value.trim() {
+                "" | "auto" => None,
+                _ => Some(parse!()),
+            },
+            "max_query_bytes" => self.max_query_bytes = parse!(),
+            "max_items_limit" => self.max_items_limit = parse!(),
+            "start_index_limit" => self.start_index_limit = parse!(),
+            "max_batch_len" => self.max_batch_len = parse!(),
+            "rate_limit_per_sec" => self.rate_limit_per_sec = parse!(),
+            "rate_limit_burst" => self.rate_limit_burst = parse!(),
+            "trusted_proxies" => self.trusted_proxies = value.split(',')
+                .map(str::trim)
+                .filter(|it| !it.is_empty())
+                .map(|it| it.parse().map_err(|e| format!("invalid IP \"{}\": {}", it, e)))
+                .collect::<Result<_, _>>()?,
+            "max_concurrent_searches" => self.max_concurrent_searches = parse!(),
+            "result_cache_bytes" => self.result_cache_bytes = parse!(),
+            _ => return Err(format!("unknown key, expected one of: {}", KEYS.join(", "))),
+        }
+        Ok(())
+    }
+
+    pub fn socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.host, self.port)
+    }
+
+    /// Value for `Access-Control-Allow-Origin`, or None if the origin is not allowed
+    pub fn cors_allow_origin(&self, origin: Option<&str>) -> Option<String> {
+        if self.cors_origins.iter().any(|it| it == "*") {
+            return Some("*".into());
+        }
+        origin
+            .filter(|origin| self.cors_origins.iter().any(|it| it == origin))
+            .map(str::to_owned)
+    }
+
+    /// Whether the `Authorization` header value grants access to `/admin/*`
+    pub fn is_admin(&self, authorization: Option<&str>) -> bool {
+        match (&self.admin_token, authorization.and_then(|it| it.strip_prefix("Bearer "))) {
+            (Some(token), Some(given)) => constant_time_eq(token.as_bytes(), given.trim().as_bytes()),
+            _ => false,
+        }
+    }
+
+    /// Hash of the settings which GET responses depend on besides the dataset, for their ETag
+    pub fn defaults_tag(&self) -> String {
+        let mut hasher = DefaultHasher::new();
+        (self.search_page_size, self.climate_page_size).hash(&mut hasher);
+        format!("{:08x}", hasher.finish() as u32)
+    }
+
+    /// Options for one request; its deadline starts now
+    pub fn request_options(&self) -> RequestOptions {
+        RequestOptions {
+            search_page_size: self.search_page_size,
+            climate_page_size: self.climate_page_size,
+            deadline: match self.search_timeout_ms {
+                0 => Deadline::none(),
+                ms => Deadline::after(Duration::from_millis(ms)),
+            },
+            max_items_limit: Some(self.max_items_limit).filter(|it| *it > 0),
+            start_index_limit: Some(self.start_index_limit).filter(|it| *it > 0),
+            max_batch_len: Some(self.max_batch_len).filter(|it| *it > 0),
+            response_format: Format::Json,
+        }
+    }
+}
+
+/// Takes as long wherever the first difference is, so that the response time doesn't tell how much of a
+/// guessed token is right; only the length may leak
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && std::hint::black_box(a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y))) == 0
+}
+
+/// Empty value means None
+fn optional(value: &str) -> Option<String> {
+    Some(value.trim())
+        .filter(|it| !it.is_empty())
+        .map(str::to_owned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn args(items: &[&str]) -> Vec<String> {
+        items.iter().map(|it| it.to_string()).collect()
+    }
+
+    fn load(items: &[&str], env: &[(&str, &str)]) -> Result<ServerConfig, String> {
+        let env = env.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<HashMap<_, _>>();
+        ServerConfig::load_from(&args(items), |name| env.get(name).cloned())
+    }
+
+    #[test]
+    fn test_defaults() {
+        assert_eq!(ServerConfig::default(), load(&[], &[]).unwrap());
+    }
+
+    #[test]
+    fn test_precedence() {
+        let path = std::env::temp_dir().join(format!("somewhere-like-test-{}.toml", std::process::id()));
+        fs::write(&path, "host = \"0.0.0.0\"\nport = 1000\nmax_body_bytes = 10\ncors_origins = [\"https://a.com\", \"https://b.com\"]\n").unwrap();
+        let path_str = path.to_str().unwrap();
+
+        let config = load(
+            &["--config", path_str, "--port=3000", "--worker-threads", "2"],
+            &[("SOMEWHERE_LIKE_PORT", "2000"), ("SOMEWHERE_LIKE_MAX_BODY_BYTES", "20")],
+        ).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(IpAddr::from([0, 0, 0, 0]), config.host);
+        assert_eq!(3000, config.port);
+        assert_eq!(20, config.max_body_bytes);
+        assert_eq!(Some(2), config.worker_threads);
+        assert_eq!(vec!["https://a.com", "https://b.com"], config.cors_origins);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(load(&["--port"], &[]).is_err());
+        assert!(load(&["--port", "abc"], &[]).is_err());
+        assert!(load(&["--no-such-key", "1"], &[]).is_err());
+        assert!(load(&["port"], &[]).is_err());
+        assert!(load(&[], &[("SOMEWHERE_LIKE_PORT", "-1")]).is_err());
+        assert!(load(&["--config", "/no/such/file.toml"], &[]).is_err());
+        assert!(load(&["--keep-alive", "yes"], &[]).is_err());
+        assert!(load(&["--tls-cert-path", "cert.pem"], &[]).is_err());
+        assert!(load(&["--rate-limit-per-sec", "-1"], &[]).is_err());
+        assert!(load(&["--rate-limit-per-sec", "NaN"], &[]).is_err());
+        assert!(load(&["--help"], &[]).unwrap_err().contains("--max-batch-len"));
+    }
+
+    #[test]
+    fn test_keys() {
+        let mut config = ServerConfig::default();
+        for key in KEYS {
+            assert!(!matches!(config.set(key, ""), Err(e) if e.starts_with("unknown key")), "{}", key);
+        }
+    }
+
+    #[test]
+    fn test_cors() {
+        let any = ServerConfig::default();
+        assert_eq!(Some("*".into()), any.cors_allow_origin(None));
+        assert_eq!(Some("*".into()), any.cors_allow_origin(Some("https://a.com")));
+
+        let listed = load(&["--cors-origins", "https://a.com, https://b.com"], &[]).unwrap();
+        assert_eq!(Some("https://b.com".into()), listed.cors_allow_origin(Some("https://b.com")));
+        assert_eq!(None, listed.cors_allow_origin(Some("https://c.com")));
+        assert_eq!(None, listed.cors_allow_origin(None));
+
+        let none = load(&["--cors-origins", ""], &[]).unwrap();
+        assert_eq!(None, none.cors_allow_origin(Some("https://a.com")));
+    }
+
+    #[test]
+    fn test_trusted_proxies() {
+        let config = load(&["--trusted-proxies", "10.0.0.1, ::1"], &[]).unwrap();
+        assert_eq!(vec![IpAddr::from([10, 0, 0, 1]), "::1".parse::<IpAddr>().unwrap()], config.trusted_proxies);
+        assert!(load(&["--trusted-proxies", "10.0.0.0/8"], &[]).is_err());
+    }
+
+    #[test]
+    fn test_defaults_tag() {
+        let config = ServerConfig::default();
+        assert_eq!(config.defaults_tag(), load(&["--port", "1"], &[]).unwrap().defaults_tag());
+        assert_ne!(config.defaults_tag(), load(&["--search-page-size", "11"], &[]).unwrap().defaults_tag());
+    }
+
+    #[test]
+    fn test_admin() {
+        let disabled = ServerConfig::default();
+        assert!(!disabled.is_admin(Some("Bearer ")));
+
+        let enabled = load(&[], &[("SOMEWHERE_LIKE_ADMIN_TOKEN", "s3cret")]).unwrap();
+        assert!(enabled.is_admin(Some("Bearer s3cret")));
+        assert!(!enabled.is_admin(Some("Bearer other")));
+        assert!(!enabled.is_admin(Some("Bearer s3cre")));
+        assert!(!enabled.is_admin(Some("Bearer s3cret2")));
+        assert!(!enabled.is_admin(Some("s3cret")));
+        assert!(!enabled.is_admin(None));
+    }
+}
@@ -0,0 +1,21 @@
+use rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+use std::sync::Arc;
+
+/// Server-side TLS settings from PEM files. ALPN offers HTTP/2 first, then HTTP/1.1.
+pub fn load_tls_config(cert_path: &str, key_path: &str) -> Result<Arc<rustls::ServerConfig>, String> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|itr| itr.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("Cannot read certificates from \"{}\": {}", cert_path, e))?;
+    if certs.is_empty() {
+        return Err(format!("No certificates in \"{}\"", cert_path));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| format!("Cannot read private key from \"{}\": {}", key_path, e))?;
+
+    let mut config = rustls::ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+        .map_err(|e| format!("Invalid TLS certificate or key: {}", e))?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
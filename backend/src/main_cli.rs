@@ -1,27 +1,292 @@
+use backend::library::api::*;
+use backend::library::api_error::{ApiError, ErrorCode};
+use backend::library::cli_output::{OutputFormat, OutputWriter};
+use backend::library::format::Format;
 use backend::library::handle_request::*;
+use backend::library::jsonl;
+use backend::library::repl::{write_cities, ReplCommand, ReplHelper, Setting, HELP};
+use clap::{error::ErrorKind, CommandFactory, Parser, Subcommand, ValueEnum};
+use common::logging::init_logging;
+use rustyline::{error::ReadlineError, CompletionType, Config, Editor};
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::Instant;
 
+const HISTORY_ENV: &str = "SOMEWHERE_LIKE_HISTORY";
 
-fn main() {
-    eprintln!("Enter city name to search by name, or id to search by climate; or use json messages");
+/// Exit statuses besides 0; with requests from stdin, the highest one of them
+const EXIT_NO_RESULTS: u8 = 1;
+/// Also for invalid arguments
+const EXIT_INVALID_REQUEST: u8 = 2;
+const EXIT_CITY_NOT_FOUND: u8 = 3;
+/// The dataset couldn't be loaded, or a request failed otherwise
+const EXIT_FAILED: u8 = 4;
 
-    loop {
-        let mut buf = String::new();
-        std::io::stdin().read_line(&mut buf).unwrap();
+/// Searches cities by name or by climate. Without a command, reads requests from stdin, one per line:
+/// a city name to search by name, an id to search by climate, "city <id> [<id>...]" to show cities,
+/// or JSON requests. In a terminal, it is an interactive shell with more commands, see :help.
+#[derive(Parser)]
+#[command(name = "cli")]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// How to print the results
+    #[arg(long, short, value_enum, default_value_t, global = true)]
+    format: OutputFormat,
+    /// For other programs: read JSON requests from stdin, with an optional "requestId", and write one
+    /// JSON response per request to stdout, with the "requestId" echoed back
+    #[arg(long, conflicts_with = "format")]
+    jsonl: bool,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Search cities by name
+    Search {
+        #[arg(required = true)]
+        query: Vec<String>,
+        #[command(flatten)]
+        page: Page,
+    },
+    /// Search cities with a climate similar to that of the city
+    Similar {
+        id: usize,
+        #[command(flatten)]
+        page: Page,
+    },
+    /// Show cities
+    City {
+        #[arg(required = true)]
+        ids: Vec<usize>,
+    },
+}
+
+#[derive(clap::Args)]
+struct Page {
+    /// Index of the first result
+    #[arg(long)]
+    start: Option<usize>,
+    /// Number of results
+    #[arg(long)]
+    max: Option<usize>,
+    /// Paths into the cities to show, e.g. names[0],climate.tmaxMonthly
+    #[arg(long, value_delimiter = ',')]
+    fields: Option<Vec<String>>,
+}
+
+impl Command {
+    fn into_request(self) -> CityRequest {
+        match self {
+            Command::Search { query, page } => CityRequest::SearchCity(CitySearchRequest {
+                query: query.join(" "),
+                start_index: page.start,
+                max_items: page.max,
+                fields: page.fields,
+                autocomplete: None,
+            }),
+            Command::Similar { id, page } => CityRequest::SearchClimate(ClimateSearchRequest {
+                city_id: id,
+                start_index: page.start,
+                max_items: page.max,
+                fields: page.fields,
+            }),
+            Command::City { ids } if ids.len() == 1 => CityRequest::GetCity(CityGetRequest { id: ids[0] }),
+            Command::City { ids } => CityRequest::GetCities(CitiesGetRequest { ids }),
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    if args.jsonl && args.command.is_some() {
+        Args::command().error(ErrorKind::ArgumentConflict, "--jsonl takes no command").exit()
+    }
+    init_logging();
+    let data = match init_data().and_then(|_| data_snapshot().map_err(|e| e.message)) {
+        Ok(data) => data,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            return ExitCode::from(EXIT_FAILED);
+        },
+    };
 
-        let command_str = buf.trim();
-        if command_str.is_empty() {
+    let mut output = OutputWriter::new(io::stdout().lock(), args.format);
+    let result = match args.command {
+        Some(command) => {
+            let response = handle_city_request(&data, command.into_request(), &RequestOptions::default());
+            print_response(response, &mut output)
+        },
+        None if args.jsonl => run_jsonl(&data),
+        None if io::stdin().is_terminal() => run_repl(data, args.format)
+            .map(|_| 0)
+            .map_err(io::Error::other),
+        None => run_stdin(&mut output),
+    };
+    match result {
+        Ok(status) => ExitCode::from(status),
+        // Such as a closed pipe, when the output is not needed anymore
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::from(EXIT_FAILED)
+        },
+    }
+}
+
+/// Handles a request per line until the end of stdin
+fn run_stdin(output: &mut OutputWriter<impl Write>) -> io::Result<u8> {
+    let mut status = 0;
+    for line in io::stdin().lock().split(b'\n') {
+        let line = String::from_utf8_lossy(&line?).trim().to_owned();
+        if !line.is_empty() {
+            status = status.max(print_response(handle_request(line, true).map(String::into_bytes), output)?);
+        }
+    }
+    Ok(status)
+}
+
+/// Answers each line of stdin with exactly one line on stdout, until the end of stdin. Failed requests
+/// are answered with an error object and don't change the exit status.
+fn run_jsonl(data: &CachedData) -> io::Result<u8> {
+    let options = RequestOptions::default();
+    let mut stdout = io::stdout().lock();
+    for line in io::stdin().lock().split(b'\n') {
+        let line = String::from_utf8_lossy(&line?).trim().to_owned();
+        if line.is_empty() {
             continue;
         }
+        let (request_id, request) = jsonl::parse_line(&line);
+        let response = request.and_then(|request| handle_city_request(data, request, &options));
+        writeln!(stdout, "{}", jsonl::format_response(request_id, response))?;
+        stdout.flush()?;
+    }
+    Ok(0)
+}
+
+/// Line editing with history and completion, for stdin in a terminal
+fn run_repl(data: Arc<CachedData>, format: OutputFormat) -> rustyline::Result<()> {
+    let config = Config::builder().completion_type(CompletionType::List).build();
+    let mut editor = Editor::with_config(config)?;
+    editor.set_helper(Some(ReplHelper::new(data.clone())));
+    let history = history_path();
+    if let Some(path) = &history {
+        // Missing on the first run
+        let _ = editor.load_history(path);
+    }
+    eprintln!("{}", HELP);
 
-        let started = std::time::Instant::now();
+    let mut output = OutputWriter::new(io::stdout(), format);
+    let mut format = format;
+    let mut options = RequestOptions::default();
+    let mut timing = false;
+    loop {
+        let line = match editor.readline("> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e),
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        editor.add_history_entry(line.as_str())?;
 
-        let response = handle_request(command_str.into(), true);
-        match response {
-            Ok(msg) => {
-                eprintln!("{}", msg);
-                eprintln!("Done \"{}\" in {} ms", command_str, started.elapsed().as_millis())
+        let command = ReplCommand::parse(&line);
+        let is_request = matches!(command, Ok(ReplCommand::Request(_) | ReplCommand::Show(_) | ReplCommand::Compare(..)));
+        let started = Instant::now();
+        match command {
+            Ok(ReplCommand::Request(request)) => {
+                print_response(handle_request_with_options(&data, request.as_bytes(), Format::Json, true, &options), &mut output)?;
+            },
+            Ok(ReplCommand::Show(id)) => print_cities(&data, vec![id], &options)?,
+            Ok(ReplCommand::Compare(a, b)) => print_cities(&data, vec![a, b], &options)?,
+            Ok(ReplCommand::Set(Some(Setting::Max(max)))) => {
+                options.search_page_size = max;
+                options.climate_page_size = max;
+            },
+            Ok(ReplCommand::Set(Some(Setting::Format(new_format)))) => {
+                format = new_format;
+                output = OutputWriter::new(io::stdout(), format);
+            },
+            Ok(ReplCommand::Set(None)) => {
+                eprintln!("max {}, format {}", options.search_page_size, format.to_possible_value().unwrap().get_name());
             },
+            Ok(ReplCommand::Timing(on)) => timing = on,
+            Ok(ReplCommand::Help) => eprintln!("{}", HELP),
+            Ok(ReplCommand::Quit) => break,
             Err(msg) => eprintln!("{}", msg),
         }
+        if timing && is_request {
+            eprintln!("Done in {} ms", started.elapsed().as_millis());
+        }
+    }
+
+    if let Some(path) = &history {
+        editor.save_history(path)?;
+    }
+    Ok(())
+}
+
+/// `SOMEWHERE_LIKE_HISTORY`, or `.somewhere_like_history` in the home directory
+fn history_path() -> Option<PathBuf> {
+    std::env::var_os(HISTORY_ENV)
+        .map(PathBuf::from)
+        .or_else(|| std::env::home_dir().map(|home| home.join(".somewhere_like_history")))
+}
+
+fn print_cities(data: &CachedData, ids: Vec<usize>, options: &RequestOptions) -> io::Result<()> {
+    match handle_city_request(data, CityRequest::GetCities(CitiesGetRequest { ids }), options) {
+        Ok(bytes) => {
+            let response = serde_json::from_slice::<serde_json::Value>(&bytes).unwrap();
+            write_cities(&mut io::stdout().lock(), response["items"].as_array().unwrap())
+        },
+        Err(err) => {
+            eprintln!("{}", err);
+            Ok(())
+        },
+    }
+}
+
+/// Writes the items to stdout and errors to stderr, returning the exit status. A batch gives an array
+/// of responses and errors.
+fn print_response(response: Result<Vec<u8>, ApiError>, output: &mut OutputWriter<impl Write>) -> io::Result<u8> {
+    let response = match response {
+        Ok(bytes) => serde_json::from_slice(&bytes).unwrap(),
+        Err(err) => {
+            eprintln!("{}", err);
+            return Ok(error_status(&err));
+        },
+    };
+    let responses = match response {
+        serde_json::Value::Array(responses) => responses,
+        response => vec![response],
+    };
+
+    let mut status = 0;
+    for response in responses {
+        let response_status = match response.get("error") {
+            Some(error) => {
+                let err = serde_json::from_value(error.clone()).unwrap();
+                eprintln!("{}", err);
+                error_status(&err)
+            },
+            None if output.write_response(&response)? == 0 => {
+                eprintln!("No results");
+                EXIT_NO_RESULTS
+            },
+            None => 0,
+        };
+        status = status.max(response_status);
+    }
+    Ok(status)
+}
+
+fn error_status(err: &ApiError) -> u8 {
+    match err.code {
+        ErrorCode::CityNotFound => EXIT_CITY_NOT_FOUND,
+        ErrorCode::BadJson | ErrorCode::UnknownCommand | ErrorCode::UnknownField | ErrorCode::LimitExceeded => EXIT_INVALID_REQUEST,
+        _ => EXIT_FAILED,
     }
 }
@@ -1,74 +1,752 @@
 use std::convert::Infallible;
-use std::net::SocketAddr;
+use std::net::IpAddr;
+use std::panic::AssertUnwindSafe;
+use std::pin::pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use backend::library::handle_request::handle_request;
+use backend::library::api::{CityGetRequest, CityRequest};
+use backend::library::api_error::{ApiError, ErrorCode};
+use backend::library::compression::Encoding;
+use backend::library::format::Format;
+use backend::library::handle_request::{data_snapshot, handle_city_request, handle_request_with_options, handle_search_stream_request, init_data, parse_search_stream_request, reload_data, set_result_cache_bytes, CachedData, ReloadResult, RequestOptions};
+use backend::library::metrics;
+use backend::library::rate_limit::{client_ip, RateLimiter};
+use backend::library::router::{route, Route, RouteError};
+use backend::library::server_config::ServerConfig;
+use backend::library::tls::load_tls_config;
+use common::city_csv::shards_stamp;
+use common::logging::init_logging;
+use futures_util::{SinkExt, StreamExt};
 use http_body_util::Full;
 use http_body_util::BodyExt;
+use http_body_util::LengthLimitError;
+use http_body_util::Limited;
 use hyper::body::Bytes;
-use hyper::server::conn::http1;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, ACCEPT, ACCEPT_ENCODING, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS, ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_HEADERS, ALLOW, AUTHORIZATION, CACHE_CONTROL, CONNECTION, CONTENT_ENCODING, CONTENT_TYPE, ETAG, FORWARDED, IF_NONE_MATCH, ORIGIN, RETRY_AFTER, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_VERSION, UPGRADE, VARY};
 use hyper::service::service_fn;
-use hyper::{Request, Response, StatusCode};
-use hyper_util::rt::TokioIo;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto;
+use tokio::io::{AsyncRead, AsyncWrite};
+use once_cell::sync::OnceCell;
 use tokio::net::TcpListener;
+use tokio::sync::{mpsc, watch, OwnedSemaphorePermit, Semaphore};
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, Role, WebSocketConfig};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+use tracing::{error, info, warn, Instrument};
+
+/// Version of the dataset which answered the request
+const X_DATASET_VERSION: HeaderName = HeaderName::from_static("x-dataset-version");
+/// Taken from the request if the client sent a sane one, otherwise generated
+const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+/// Larger bodies are compressed in the blocking pool rather than on the reactor
+const INLINE_COMPRESSION_MAX_BYTES: usize = 16 * 1024;
+
+/// Limits how many requests occupy the blocking pool, see `run_blocking`
+static SEARCH_SLOTS: OnceCell<Arc<Semaphore>> = OnceCell::new();
+/// Limits how many requests occupy or wait for the blocking pool; None if unlimited
+static SEARCH_ADMISSIONS: OnceCell<Option<Arc<Semaphore>>> = OnceCell::new();
+/// None if rate limiting is disabled
+static RATE_LIMITER: OnceCell<Option<RateLimiter>> = OnceCell::new();
+
+/// Held by every connection and WebSocket session, so that they learn about the shutdown,
+/// and `serve` learns when all of them are closed
+#[derive(Clone)]
+struct ShutdownHandle {
+    requested: watch::Receiver<bool>,
+    _alive: mpsc::Sender<()>,
+}
+
+impl ShutdownHandle {
+    async fn requested(&mut self) {
+        // Err means `serve` is gone, which is a shutdown as well
+        let _ = self.requested.wait_for(|requested| *requested).await;
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    init_logging();
+    let config = match ServerConfig::load() {
+        Ok(config) => Arc::new(config),
+        Err(msg) => {
+            error!("{}", msg);
+            std::process::exit(2);
+        }
+    };
+
+    let mut runtime_builder = tokio::runtime::Builder::new_multi_thread();
+    if let Some(worker_threads) = config.worker_threads {
+        runtime_builder.worker_threads(worker_threads);
+    }
+    let runtime = runtime_builder.enable_all().build()?;
+    let result = runtime.block_on(serve(config));
+    // Don't wait for the data loading if the shutdown was requested before it finished
+    runtime.shutdown_background();
+    result
+}
 
 /// https://hyper.rs/guides/1/server/hello-world/
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let addr = SocketAddr::from(([127, 0, 0, 1], 3001));
+async fn serve(config: Arc<ServerConfig>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let addr = config.socket_addr();
+
+    let tls_acceptor = match (&config.tls_cert_path, &config.tls_key_path) {
+        (Some(cert_path), Some(key_path)) => Some(TlsAcceptor::from(load_tls_config(cert_path, key_path)?)),
+        _ => None,
+    };
+    let scheme = if tls_acceptor.is_some() { "https" } else { "http" };
+    let builder = Arc::new(conn_builder(&config));
+    let search_concurrency = config.search_concurrency
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |it| it.get()));
+    SEARCH_SLOTS.set(Arc::new(Semaphore::new(search_concurrency))).unwrap();
+    SEARCH_ADMISSIONS.set(
+        Some(config.max_concurrent_searches).filter(|it| *it > 0).map(|it| Arc::new(Semaphore::new(it)))
+    ).unwrap();
+    RATE_LIMITER.set(
+        Some(config.rate_limit_per_sec).filter(|it| *it > 0.0).map(|it| RateLimiter::new(it, config.rate_limit_burst))
+    ).unwrap();
+    set_result_cache_bytes(config.result_cache_bytes);
 
     let listener = TcpListener::bind(addr).await?;
 
-    eprintln!("Listening on http://{}", addr);
+    // Until the data is loaded, /healthz and /readyz already answer, other routes respond 503
+    info!("Bound to {}://{}, loading data", scheme, addr);
+    let reload_poll_secs = config.reload_poll_secs;
+    tokio::task::spawn(async move {
+        match tokio::task::spawn_blocking(init_data).await {
+            Ok(Ok(())) => {
+                info!("Listening on {}://{}", scheme, addr);
+                if reload_poll_secs > 0 {
+                    tokio::task::spawn(poll_shards(Duration::from_secs(reload_poll_secs)));
+                }
+            },
+            Ok(Err(msg)) => {
+                error!("{}", msg);
+                std::process::exit(1);
+            }
+            Err(err) => {
+                error!("Data loading failed: {}", err);
+                std::process::exit(1);
+            }
+        }
+    });
+
+    let (request_shutdown, shutdown_requested) = watch::channel(false);
+    let (alive, mut all_closed) = mpsc::channel(1);
+    let shutdown_handle = ShutdownHandle { requested: shutdown_requested, _alive: alive };
+    let mut shutdown = pin!(shutdown_signal());
 
     loop {
-        let (stream, _) = listener.accept().await?;
-        let io = TokioIo::new(stream);
-        tokio::task::spawn(async move {
-            if let Err(err) = http1::Builder::new()
-                .serve_connection(io, service_fn(handle))
-                .await
-            {
-                eprintln!("Error serving connection: {:?}", err);
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, peer_addr) = accepted?;
+                let peer_ip = peer_addr.ip();
+                let builder = builder.clone();
+                let shutdown = shutdown_handle.clone();
+                let config = config.clone();
+                match &tls_acceptor {
+                    Some(tls_acceptor) => {
+                        let tls_acceptor = tls_acceptor.clone();
+                        tokio::task::spawn(async move {
+                            match tls_acceptor.accept(stream).await {
+                                Ok(tls_stream) => serve_connection(tls_stream, peer_ip, &builder, shutdown, config).await,
+                                Err(err) => warn!("TLS handshake failed: {}", err),
+                            }
+                        });
+                    },
+                    None => {
+                        tokio::task::spawn(async move {
+                            serve_connection(stream, peer_ip, &builder, shutdown, config).await
+                        });
+                    },
+                }
+            }
+            _ = &mut shutdown => {
+                info!("Shutting down, draining connections");
+                break;
             }
-        });
+        }
     }
+
+    drop(listener);
+    request_shutdown.send_replace(true);
+    drop(shutdown_handle);
+    tokio::select! {
+        _ = all_closed.recv() => info!("All connections closed"),
+        _ = tokio::time::sleep(Duration::from_secs(config.shutdown_timeout_secs)) => {
+            warn!("Connections not closed in {} s, exiting anyway", config.shutdown_timeout_secs);
+        }
+    }
+    Ok(())
 }
 
-async fn handle(req: Request<hyper::body::Incoming>) -> Result<Response<Full<Bytes>>, Infallible> {
-    let req_body = req.into_body().collect().await;
-    match req_body {
+/// Serves HTTP/1.1 and HTTP/2 on the same port: over TLS the protocol is chosen with ALPN,
+/// and on plain TCP HTTP/2 is recognized by its preface (h2c with prior knowledge)
+fn conn_builder(config: &ServerConfig) -> auto::Builder<TokioExecutor> {
+    let mut builder = auto::Builder::new(TokioExecutor::new());
+    builder.http1()
+        .timer(TokioTimer::new())
+        .keep_alive(config.keep_alive);
+    let mut http2 = builder.http2();
+    http2.timer(TokioTimer::new());
+    if config.h2_keep_alive_interval_secs > 0 {
+        http2
+            .keep_alive_interval(Duration::from_secs(config.h2_keep_alive_interval_secs))
+            .keep_alive_timeout(Duration::from_secs(config.h2_keep_alive_timeout_secs));
+    }
+    builder
+}
+
+async fn serve_connection(
+    stream: impl AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    peer_ip: IpAddr,
+    builder: &auto::Builder<TokioExecutor>,
+    mut shutdown: ShutdownHandle,
+    config: Arc<ServerConfig>,
+) {
+    let service_shutdown = shutdown.clone();
+    let mut conn = pin!(builder.serve_connection_with_upgrades(
+        TokioIo::new(stream),
+        service_fn(move |req| handle(req, peer_ip, config.clone(), service_shutdown.clone())),
+    ));
+    let result = tokio::select! {
+        result = conn.as_mut() => result,
+        _ = shutdown.requested() => {
+            conn.as_mut().graceful_shutdown();
+            conn.await
+        },
+    };
+    if let Err(err) = result {
+        warn!("Error serving connection: {:?}", err);
+    }
+}
+
+/// Reloads the data when the shards change. Waits until they stay the same for one more period,
+/// so that shards which are being written are not read.
+async fn poll_shards(period: Duration) {
+    let mut loaded_stamp = tokio::task::spawn_blocking(shards_stamp).await.ok().flatten();
+    let mut seen_stamp = loaded_stamp;
+    let mut interval = tokio::time::interval(period);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let stamp = tokio::task::spawn_blocking(shards_stamp).await.ok().flatten();
+        if stamp.is_none() || stamp == loaded_stamp {
+            seen_stamp = stamp;
+            continue;
+        }
+        if stamp != seen_stamp {
+            seen_stamp = stamp;
+            continue;
+        }
+        info!("Shards changed, reloading");
+        if run_reload().await.is_ok() {
+            loaded_stamp = stamp;
+        }
+    }
+}
+
+/// Reloads in a blocking thread; requests keep being served from the current data meanwhile
+async fn run_reload() -> Result<ReloadResult, String> {
+    let result = tokio::task::spawn_blocking(reload_data).await
+        .unwrap_or_else(|err| Err(format!("Reload failed: {}", err)));
+    match &result {
+        Ok(ReloadResult { version, changed: true }) => info!(%version, "Reloaded dataset"),
+        Ok(ReloadResult { version, changed: false }) => info!(%version, "Dataset is unchanged"),
+        Err(msg) => error!("Reload failed, keeping the current data: {}", msg),
+    }
+    result
+}
+
+/// Resolves on Ctrl+C (SIGINT) or SIGTERM
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.expect("Cannot install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Cannot install SIGTERM handler")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+async fn handle(req: Request<hyper::body::Incoming>, peer_ip: IpAddr, config: Arc<ServerConfig>, shutdown: ShutdownHandle) -> Result<Response<Full<Bytes>>, Infallible> {
+    let request_id = req.headers().get(X_REQUEST_ID)
+        .and_then(|it| it.to_str().ok())
+        .filter(|it| is_sane_request_id(it))
+        .map(str::to_owned)
+        .unwrap_or_else(|| format!("{:016x}", rand::random::<u64>()));
+    let client_ip = client_ip(
+        peer_ip,
+        joined_header(req.headers(), FORWARDED).as_deref(),
+        joined_header(req.headers(), X_FORWARDED_FOR).as_deref(),
+        &config.trusted_proxies,
+    );
+    let span = tracing::info_span!("request", %request_id, %client_ip, method = %req.method(), path = req.uri().path());
+
+    async move {
+        let started = Instant::now();
+        let mut resp = handle_impl(req, client_ip, config, shutdown).await;
+        resp.headers_mut().insert(X_REQUEST_ID, request_id.parse().unwrap());
+        info!(status = resp.status().as_u16(), elapsed_ms = started.elapsed().as_micros() as f64 / 1000.0, "Request completed");
+        Ok(resp)
+    }.instrument(span).await
+}
+
+fn is_sane_request_id(request_id: &str) -> bool {
+    (1..=128).contains(&request_id.len())
+        && request_id.chars().all(|c| c.is_ascii_alphanumeric() || "-_.:".contains(c))
+}
+
+async fn handle_impl(req: Request<hyper::body::Incoming>, client_ip: IpAddr, config: Arc<ServerConfig>, shutdown: ShutdownHandle) -> Response<Full<Bytes>> {
+    let allow_origin = config.cors_allow_origin(
+        req.headers().get(ORIGIN).and_then(|it| it.to_str().ok())
+    );
+    let encoding = Encoding::negotiate(req.headers().get(ACCEPT_ENCODING).and_then(|it| it.to_str().ok()));
+    let format = Format::negotiate(req.headers().get(ACCEPT).and_then(|it| it.to_str().ok()));
+
+    let query_bytes = req.uri().query().map_or(0, str::len);
+    let routed = route(req.method(), req.uri().path(), req.uri().query());
+    let resp = if req.method() == Method::OPTIONS {
+        preflight_resp(&req, allow_origin.is_some())
+    } else if query_bytes > config.max_query_bytes {
+        error_resp(&ApiError::new(ErrorCode::LimitExceeded, format!("Query string exceeds {} bytes", config.max_query_bytes)))
+    } else if let Err(retry_after) = check_rate_limit(client_ip, &routed) {
+        rate_limited_resp(retry_after)
+    } else {
+        match routed {
+            Ok(Route::Command) => handle_body(req, &config, format).await,
+            Ok(Route::SearchStream) => handle_search_stream(req, client_ip, &config, shutdown),
+            Ok(Route::Health) => json_resp(r#"{"status":"ok"}"#.into()),
+            Ok(Route::Ready) => match data_snapshot() {
+                Ok(data) => versioned(json_resp(format!(r#"{{"status":"ready","datasetVersion":"{}"}}"#, data.version())), &data),
+                Err(err) => error_resp(&err),
+            },
+            Ok(Route::AdminReload) => handle_admin_reload(&req, &config).await,
+            Ok(Route::Metrics) => {
+                let mut resp = ok_resp(metrics::gather());
+                resp.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static(metrics::CONTENT_TYPE));
+                resp
+            },
+            Ok(get_route) => handle_get(&req, get_route, &config, format).await,
+            Err(err) => route_error_resp(err),
+        }
+    };
+    let mut resp = compress_resp(resp, encoding, config.compression_min_bytes).await;
+
+    if let Some(origin) = allow_origin {
+        resp.headers_mut().insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin.parse().unwrap());
+        resp.headers_mut().insert(ACCESS_CONTROL_EXPOSE_HEADERS, HeaderValue::from_static("X-Dataset-Version, X-Request-Id"));
+    }
+    if !config.cors_origins.iter().any(|it| it == "*") {
+        resp.headers_mut().append(VARY, HeaderValue::from_static("Origin"));
+    }
+    resp
+}
+
+/// The body is read as `Content-Type` says, and the response is serialized in `format`
+async fn handle_body(req: Request<hyper::body::Incoming>, config: &ServerConfig, format: Format) -> Response<Full<Bytes>> {
+    let req_format = Format::from_content_type(req.headers().get(CONTENT_TYPE).and_then(|it| it.to_str().ok()));
+    let req_body = Limited::new(req.into_body(), config.max_body_bytes).collect().await;
+    let data = match data_snapshot() {
+        Ok(data) => data,
+        Err(err) => return error_resp(&err),
+    };
+    let response = match req_body {
         Ok(collected) => {
-            let req_body_bytes = collected.to_bytes().into_iter().collect::<Vec<_>>();
-            match String::from_utf8(req_body_bytes) {
-                Ok(req_body_utf8) => {
-                    match handle_request(req_body_utf8, false) {
-                        Ok(response) => {
-                            Ok(ok_resp(response))
-                        }
-                        Err(msg) => {
-                            Ok(bad_req_resp(msg))
-                        }
-                    }
+            let req_body_bytes = collected.to_bytes();
+            let data = data.clone();
+            let options = RequestOptions { response_format: format, ..config.request_options() };
+            run_blocking(options, move |options| {
+                handle_request_with_options(&data, &req_body_bytes, req_format, false, options)
+            }).await
+        }
+        Err(err) if err.is::<LengthLimitError>() => {
+            Err(ApiError::new(ErrorCode::LimitExceeded, format!("Request body exceeds {} bytes", config.max_body_bytes)))
+        }
+        Err(err) => {
+            Err(ApiError::new(ErrorCode::BadJson, err.to_string()))
+        }
+    };
+    let resp = match response {
+        Ok(response) => format_resp(response, format),
+        Err(err) => format_error_resp(&err, format),
+    };
+    versioned(resp, &data)
+}
+
+/// Compresses the body if it's large enough and the client accepts one of the supported encodings
+async fn compress_resp(resp: Response<Full<Bytes>>, encoding: Encoding, min_bytes: usize) -> Response<Full<Bytes>> {
+    let (mut parts, body) = resp.into_parts();
+    let body = match body.collect().await {
+        Ok(collected) => collected.to_bytes(),
+        Err(infallible) => match infallible {},
+    };
+    if body.len() < min_bytes || parts.status == StatusCode::SWITCHING_PROTOCOLS {
+        return Response::from_parts(parts, Full::new(body));
+    }
+
+    parts.headers.append(VARY, HeaderValue::from_static("Accept-Encoding"));
+    let Some(content_encoding) = encoding.header_value() else {
+        return Response::from_parts(parts, Full::new(body));
+    };
+    let compressed = if body.len() <= INLINE_COMPRESSION_MAX_BYTES {
+        encoding.compress(&body)
+    } else {
+        let uncompressed = body.clone();
+        match tokio::task::spawn_blocking(move || encoding.compress(&uncompressed)).await {
+            Ok(compressed) => compressed,
+            Err(err) => {
+                error!("Compression failed: {}", err);
+                return Response::from_parts(parts, Full::new(body));
+            },
+        }
+    };
+    parts.headers.insert(CONTENT_ENCODING, HeaderValue::from_static(content_encoding));
+    Response::from_parts(parts, Full::new(Bytes::from(compressed)))
+}
+
+/// Returns 404 if no admin token is configured, as if the route didn't exist
+async fn handle_admin_reload(req: &Request<hyper::body::Incoming>, config: &ServerConfig) -> Response<Full<Bytes>> {
+    if config.admin_token.is_none() {
+        return route_error_resp(RouteError::NotFound);
+    }
+    if !config.is_admin(req.headers().get(AUTHORIZATION).and_then(|it| it.to_str().ok())) {
+        return error_resp(&ApiError::new(ErrorCode::Unauthorized, "Missing or wrong admin token"));
+    }
+    match run_reload().await {
+        Ok(ReloadResult { version, changed }) => json_resp(format!(
+            r#"{{"status":"{}","datasetVersion":"{}"}}"#,
+            if changed { "reloaded" } else { "unchanged" },
+            version,
+        )),
+        Err(msg) => error_resp(&ApiError::new(ErrorCode::Internal, msg)),
+    }
+}
+
+/// GET responses only change with the dataset, so they are cached by the dataset version and the format
+async fn handle_get(req: &Request<hyper::body::Incoming>, get_route: Route, config: &ServerConfig, format: Format) -> Response<Full<Bytes>> {
+    let data = match data_snapshot() {
+        Ok(data) => data,
+        Err(err) => return error_resp(&err),
+    };
+    let etag = match format {
+        Format::Json => format!("W/\"{}-{}\"", data.version(), config.defaults_tag()),
+        Format::MessagePack => format!("W/\"{}-{}-msgpack\"", data.version(), config.defaults_tag()),
+        Format::Cbor => format!("W/\"{}-{}-cbor\"", data.version(), config.defaults_tag()),
+    };
+    let request = match get_route {
+        Route::GetCity(id) => CityRequest::GetCity(CityGetRequest { id }),
+        Route::SearchCities(search_req) => CityRequest::SearchCity(search_req),
+        Route::SimilarCities(climate_req) => CityRequest::SearchClimate(climate_req),
+        Route::Command | Route::SearchStream | Route::Health | Route::Ready | Route::Metrics | Route::AdminReload => unreachable!(),
+    };
+    let options = RequestOptions { response_format: format, ..config.request_options() };
+    let deadline = options.deadline.clone();
+    let handler_data = data.clone();
+    // Run even if the client has it cached, since only an existing resource may be answered with 304
+    let result = run_blocking(options, move |options| {
+        handle_city_request(&handler_data, request, options)
+    }).await;
+    let not_modified = req.headers().get(IF_NONE_MATCH)
+        .and_then(|it| it.to_str().ok())
+        .is_some_and(|it| it.split(',').any(|tag| tag.trim() == etag || tag.trim() == "*"));
+    let mut resp = match result {
+        Ok(_) if not_modified => status_resp(StatusCode::NOT_MODIFIED, String::new()),
+        // Possibly truncated, so not worth caching
+        Ok(body) if deadline.is_expired() => return versioned(format_resp(body, format), &data),
+        Ok(body) => format_resp(body, format),
+        Err(err) => return versioned(format_error_resp(&err, format), &data),
+    };
+
+    let headers = resp.headers_mut();
+    headers.append(VARY, HeaderValue::from_static("Accept"));
+    headers.insert(ETAG, etag.parse().unwrap());
+    headers.insert(CACHE_CONTROL, format!("public, max-age={}", config.cache_max_age_secs).parse().unwrap());
+    versioned(resp, &data)
+}
+
+fn versioned(mut resp: Response<Full<Bytes>>, data: &CachedData) -> Response<Full<Bytes>> {
+    resp.headers_mut().insert(X_DATASET_VERSION, data.version().parse().unwrap());
+    resp
+}
+
+/// `GET /cities/stream`: answers the WebSocket handshake, then serves the session in its own task.
+/// Only HTTP/1.1 can upgrade; other requests get 426.
+fn handle_search_stream(mut req: Request<hyper::body::Incoming>, client_ip: IpAddr, config: &Arc<ServerConfig>, shutdown: ShutdownHandle) -> Response<Full<Bytes>> {
+    let headers = req.headers();
+    let key = headers.get(SEC_WEBSOCKET_KEY);
+    let is_handshake = has_token(headers, CONNECTION, "upgrade")
+        && has_token(headers, UPGRADE, "websocket")
+        && headers.get(SEC_WEBSOCKET_VERSION).is_some_and(|it| it == "13");
+    let Some(key) = key.filter(|_| is_handshake) else {
+        let mut resp = error_resp(&ApiError::new(ErrorCode::UpgradeRequired, "Connect with a WebSocket"));
+        resp.headers_mut().insert(UPGRADE, HeaderValue::from_static("websocket"));
+        resp.headers_mut().insert(SEC_WEBSOCKET_VERSION, HeaderValue::from_static("13"));
+        return resp;
+    };
+    let accept = derive_accept_key(key.as_bytes());
+
+    let on_upgrade = hyper::upgrade::on(&mut req);
+    let config = config.clone();
+    tokio::task::spawn(async move {
+        match on_upgrade.await {
+            Ok(upgraded) => {
+                let ws_config = WebSocketConfig::default()
+                    .max_message_size(Some(config.max_body_bytes))
+                    .max_frame_size(Some(config.max_body_bytes));
+                let ws = WebSocketStream::from_raw_socket(TokioIo::new(upgraded), Role::Server, Some(ws_config)).await;
+                serve_search_stream(ws, client_ip, &config, shutdown).await;
+            },
+            Err(err) => warn!("WebSocket upgrade failed: {}", err),
+        }
+    }.instrument(tracing::Span::current()));
+
+    let mut resp = status_resp(StatusCode::SWITCHING_PROTOCOLS, String::new());
+    let headers = resp.headers_mut();
+    headers.insert(CONNECTION, HeaderValue::from_static("upgrade"));
+    headers.insert(UPGRADE, HeaderValue::from_static("websocket"));
+    headers.insert(SEC_WEBSOCKET_ACCEPT, accept.parse().unwrap());
+    resp
+}
+
+/// Each text message is a `searchCity` command, which supersedes the previous one: its search is
+/// cancelled, and its results which are still queued are not sent. Every message takes a rate limit token.
+async fn serve_search_stream(
+    ws: WebSocketStream<TokioIo<hyper::upgrade::Upgraded>>,
+    client_ip: IpAddr,
+    config: &ServerConfig,
+    mut shutdown: ShutdownHandle,
+) {
+    let (mut ws_sender, mut ws_receiver) = ws.split();
+    // Messages tagged with the sequence number of the query they answer
+    let (sender, mut receiver) = mpsc::unbounded_channel::<(u64, String)>();
+    let mut latest = 0;
+    let mut running: Option<tokio::task::JoinHandle<()>> = None;
+
+    let close_frame = loop {
+        tokio::select! {
+            message = ws_receiver.next() => {
+                let text = match message {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Binary(_))) => {
+                        let err = ApiError::new(ErrorCode::BadJson, "Expected a text message");
+                        let _ = sender.send((latest, err.to_json()));
+                        continue;
+                    },
+                    Some(Ok(Message::Close(_))) | None => break None,
+                    Some(Ok(_)) => continue,
+                    Some(Err(err)) => {
+                        tracing::debug!("WebSocket error: {}", err);
+                        break None;
+                    },
+                };
+                if let Some(task) = running.take() {
+                    task.abort();
                 }
-                Err(msg) => {
-                    Ok(bad_req_resp(msg.to_string()))
+                latest += 1;
+                let seq = latest;
+                let request = take_rate_token(client_ip)
+                    .map_err(|retry_after| rate_limited_error(retry_after_secs(retry_after)))
+                    .and_then(|_| parse_search_stream_request(text.as_str()))
+                    .and_then(|req| Ok((req, data_snapshot()?)));
+                let (req, data) = match request {
+                    Ok(request) => request,
+                    Err(err) => {
+                        let _ = sender.send((seq, err.to_json()));
+                        continue;
+                    },
+                };
+                let sender = sender.clone();
+                let options = config.request_options();
+                running = Some(tokio::task::spawn(async move {
+                    let partial_sender = sender.clone();
+                    let result = run_blocking(options, move |options| {
+                        handle_search_stream_request(&data, req, options, |partial| {
+                            let _ = partial_sender.send((seq, partial));
+                        })
+                    }).await;
+                    let _ = sender.send((seq, result.unwrap_or_else(|err| err.to_json())));
+                }.instrument(tracing::Span::current())));
+            },
+            Some((seq, message)) = receiver.recv() => {
+                if seq == latest && ws_sender.send(Message::text(message)).await.is_err() {
+                    break None;
                 }
-            }
-        }
-        Err(msg) => {
-            Ok(bad_req_resp(msg.to_string()))
+            },
+            _ = shutdown.requested() => {
+                break Some(CloseFrame { code: CloseCode::Away, reason: "Server is shutting down".into() });
+            },
         }
+    };
+
+    if let Some(task) = running {
+        task.abort();
+    }
+    // Fails if the peer already closed, which is fine
+    let _ = ws_sender.send(Message::Close(close_frame)).await;
+}
+
+/// All values of a header, joined with commas; None if it is missing
+fn joined_header(headers: &HeaderMap, name: HeaderName) -> Option<String> {
+    let values = headers.get_all(name).iter()
+        .filter_map(|it| it.to_str().ok())
+        .collect::<Vec<_>>();
+    Some(values.join(",")).filter(|_| !values.is_empty())
+}
+
+/// Whether a comma-separated header has the token, case-insensitively
+fn has_token(headers: &HeaderMap, name: HeaderName, token: &str) -> bool {
+    headers.get_all(name).iter()
+        .filter_map(|it| it.to_str().ok())
+        .flat_map(|it| it.split(','))
+        .any(|it| it.trim().eq_ignore_ascii_case(token))
+}
+
+/// Data routes take a token of the client's bucket; Err is the time until the next token
+fn check_rate_limit(client_ip: IpAddr, routed: &Result<Route, RouteError>) -> Result<(), Duration> {
+    match routed {
+        Ok(route) if route.is_data() => take_rate_token(client_ip),
+        _ => Ok(()),
+    }
+}
+
+fn take_rate_token(client_ip: IpAddr) -> Result<(), Duration> {
+    RATE_LIMITER.get().and_then(Option::as_ref).map_or(Ok(()), |limiter| limiter.check(client_ip))
+}
+
+fn retry_after_secs(retry_after: Duration) -> u64 {
+    retry_after.as_secs_f64().ceil().max(1.0) as u64
+}
+
+fn rate_limited_error(retry_after_secs: u64) -> ApiError {
+    ApiError::new(ErrorCode::TooManyRequests, format!("Rate limit exceeded, retry in {} s", retry_after_secs))
+}
+
+fn rate_limited_resp(retry_after: Duration) -> Response<Full<Bytes>> {
+    let retry_after_secs = retry_after_secs(retry_after);
+    let mut resp = error_resp(&rate_limited_error(retry_after_secs));
+    resp.headers_mut().insert(RETRY_AFTER, retry_after_secs.into());
+    resp
+}
+
+/// Fails with `tooManyRequests` if `max_concurrent_searches` requests are already handled or waiting
+fn admit_search() -> Result<Option<OwnedSemaphorePermit>, ApiError> {
+    match SEARCH_ADMISSIONS.get().unwrap() {
+        Some(admissions) => admissions.clone().try_acquire_owned()
+            .map(Some)
+            .map_err(|_| ApiError::new(ErrorCode::TooManyRequests, "Too many concurrent searches, retry later")),
+        None => Ok(None),
+    }
+}
+
+/// Runs the handler in the blocking pool, so that a long search doesn't stall the reactor, at most
+/// `search_concurrency` at once. If no slot frees up within the time budget, fails with `timeout`.
+/// If this future is dropped, e.g. because the client disconnected, the deadline is cancelled,
+/// so the search stops early.
+async fn run_blocking<T: Send + 'static>(
+    options: RequestOptions,
+    handler: impl FnOnce(&RequestOptions) -> Result<T, ApiError> + Send + 'static,
+) -> Result<T, ApiError> {
+    let admission = admit_search()?;
+    let cancel_on_drop = options.deadline.cancel_on_drop();
+
+    let slots = SEARCH_SLOTS.get().unwrap().clone();
+    let permit = match options.deadline.remaining() {
+        Some(remaining) => tokio::time::timeout(remaining, slots.acquire_owned()).await
+            .map_err(|_| ApiError::new(ErrorCode::Timeout, "Server is busy, no search slot freed up in time"))?,
+        None => slots.acquire_owned().await,
+    }.unwrap();
+
+    let span = tracing::Span::current();
+    let result = tokio::task::spawn_blocking(move || {
+        let _permits = (admission, permit);
+        let _entered = span.enter();
+        catch_internal(|| handler(&options))
+    }).await.unwrap_or_else(|_| Err(ApiError::new(ErrorCode::Internal, "Internal error")));
+    cancel_on_drop.disarm();
+    result
+}
+
+/// A panic while handling a request results in 500 instead of a dropped connection
+fn catch_internal<T>(handler: impl FnOnce() -> Result<T, ApiError>) -> Result<T, ApiError> {
+    std::panic::catch_unwind(AssertUnwindSafe(handler))
+        .unwrap_or_else(|_| Err(ApiError::new(ErrorCode::Internal, "Internal error")))
+}
+
+fn route_error_resp(err: RouteError) -> Response<Full<Bytes>> {
+    let allow = match err {
+        RouteError::MethodNotAllowed(allow) => Some(allow),
+        _ => None,
+    };
+    let mut resp = error_resp(&err.into());
+    if let Some(allow) = allow {
+        resp.headers_mut().insert(ALLOW, HeaderValue::from_static(allow));
+    }
+    resp
+}
+
+/// https://developer.mozilla.org/en-US/docs/Glossary/Preflight_request
+fn preflight_resp(req: &Request<hyper::body::Incoming>, origin_allowed: bool) -> Response<Full<Bytes>> {
+    let mut resp = status_resp(StatusCode::NO_CONTENT, String::new());
+    if origin_allowed {
+        let headers = resp.headers_mut();
+        headers.insert(ACCESS_CONTROL_ALLOW_METHODS, HeaderValue::from_static("GET, POST, OPTIONS"));
+        headers.insert(
+            ACCESS_CONTROL_ALLOW_HEADERS,
+            req.headers().get(ACCESS_CONTROL_REQUEST_HEADERS).cloned().unwrap_or(HeaderValue::from_static("Content-Type")),
+        );
+        headers.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from_static("86400"));
     }
+    resp
 }
 
-fn bad_req_resp(msg: String) -> Response<Full<Bytes>> {
+fn error_resp(err: &ApiError) -> Response<Full<Bytes>> {
+    let mut resp = json_resp(err.to_json());
+    *resp.status_mut() = err.http_status();
+    resp
+}
+
+/// Errors of commands are serialized like their responses
+fn format_error_resp(err: &ApiError, format: Format) -> Response<Full<Bytes>> {
+    let mut resp = format_resp(format.serialize_error(err), format);
+    *resp.status_mut() = err.http_status();
+    resp
+}
+
+fn format_resp(body: Vec<u8>, format: Format) -> Response<Full<Bytes>> {
+    let mut resp = Response::new(Full::new(Bytes::from(body)));
+    resp.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static(format.content_type()));
+    resp
+}
+
+fn status_resp(status: StatusCode, msg: String) -> Response<Full<Bytes>> {
     let mut resp = ok_resp(msg);
-    *resp.status_mut() = StatusCode::BAD_REQUEST;
+    *resp.status_mut() = status;
     resp
 }
 
-fn ok_resp(str: String) -> Response<Full<Bytes>> {
-    let mut resp =Response::new(Full::new(Bytes::from(str)));
-    resp.headers_mut().insert("Access-Control-Allow-Origin", "*".parse().unwrap());
+fn json_resp(str: String) -> Response<Full<Bytes>> {
+    let mut resp = ok_resp(str);
+    resp.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
     resp
 }
+
+fn ok_resp(str: String) -> Response<Full<Bytes>> {
+    Response::new(Full::new(Bytes::from(str)))
+}
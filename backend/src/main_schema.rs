@@ -0,0 +1,16 @@
+use backend::library::schema::generated_files;
+
+/// Writes the OpenAPI document and the TypeScript types generated from the API types
+fn main() {
+    for (path, contents) in generated_files() {
+        if std::fs::read_to_string(&path).is_ok_and(|it| it == contents) {
+            eprintln!("Up to date: {}", path.display());
+            continue;
+        }
+        if let Err(err) = std::fs::write(&path, contents) {
+            eprintln!("Cannot write {}: {}", path.display(), err);
+            std::process::exit(1);
+        }
+        eprintln!("Wrote {}", path.display());
+    }
+}
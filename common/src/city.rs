@@ -1,8 +1,9 @@
 use chrono::NaiveDate;
 use derive_csv_friendly::CsvFriendly;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, CsvFriendly, Serialize, Deserialize)]
+#[derive(Debug, CsvFriendly, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct City {
     pub names: Vec<String>,
@@ -17,7 +18,7 @@ pub struct City {
     pub climate: CityClimate,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct CityClimate {
     pub humidity_monthly: [Option<f32>; 12],
@@ -1,7 +1,6 @@
-use core::panic;
-use std::fs::File;
+use std::{fs::File, hash::{DefaultHasher, Hash, Hasher}, path::Path};
 use rayon::prelude::*;
-use crate::{city::{CityCsvFriendly, City}, util::get_data_out_dir};
+use crate::{city::{CityCsvFriendly, City}, util::{get_data_out_dir, DATA_DIR_ENV}};
 
 const SHARD_SIZE: usize = 2_000;
 const EXPECTED_SHARDS_NUM: usize = 32;
@@ -11,53 +10,139 @@ pub fn get_file_name(shard: usize) -> String {
     format!("cities-{:0fill$}.csv", shard, fill = max_shard_str_len)
 }
 
-pub fn write_cities(cities: Vec<City>) {
+pub fn write_cities(cities: Vec<City>) -> Result<(), String> {
+    write_cities_to(&get_data_out_dir(), cities)
+}
+
+/// Writes the cities into shards in `dir`, creating it if needed. Returns a readable error if it can't.
+///
+/// ```
+/// let err = common::city_csv::write_cities_to(std::path::Path::new("/no/such/dir"), Vec::new()).unwrap_err();
+/// assert_eq!("Expected 32 shards, got 0", err);
+/// ```
+pub fn write_cities_to(dir: &Path, cities: Vec<City>) -> Result<(), String> {
     let actual_shards_num = (cities.len() as f64 / SHARD_SIZE as f64).ceil() as usize;
     if actual_shards_num != EXPECTED_SHARDS_NUM {
-        panic!("Expected {} shards, got {}", EXPECTED_SHARDS_NUM, actual_shards_num);
+        return Err(format!("Expected {} shards, got {}", EXPECTED_SHARDS_NUM, actual_shards_num));
     }
 
+    std::fs::create_dir_all(dir).map_err(|e| format!("Cannot create {:?}: {}", dir, e))?;
+
     let csv_cities = cities.into_par_iter()
         .map(CityCsvFriendly::from)
         .collect::<Vec<_>>();
 
-    let total_size: u64 = csv_cities.par_chunks(SHARD_SIZE).enumerate()
+    let total_size = csv_cities.par_chunks(SHARD_SIZE).enumerate()
         .map(|(shard, chunk)| {
-            let path = get_data_out_dir().join(get_file_name(shard));
-            let file = File::create(path).unwrap();
+            let path = dir.join(get_file_name(shard));
+            let write_error = |e: &dyn std::fmt::Display| format!("Cannot write {:?}: {}", path, e);
+            let file = File::create(&path).map_err(|e| write_error(&e))?;
             let mut writer = csv::WriterBuilder::new()
                 .has_headers(false)
                 .delimiter(b'\t')
                 .from_writer(&file);
-            chunk.into_iter().for_each(
-                |c| writer.serialize(c).unwrap()
-            );
-            writer.flush().unwrap();
-            file.metadata().unwrap().len()
+            for c in chunk {
+                writer.serialize(c).map_err(|e| write_error(&e))?;
+            }
+            writer.flush().map_err(|e| write_error(&e))?;
+            file.metadata().map(|it| it.len()).map_err(|e| write_error(&e))
         })
-        .sum();
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .sum::<u64>();
+
+    tracing::info!(size_mb = format!("{:.1}", (total_size as f64) / 1024.0 / 1024.0), shards = actual_shards_num, ?dir, "Written cities");
+    Ok(())
+}
+
+pub struct Dataset {
+    pub cities: Vec<City>,
+    /// Hash of all shards contents, changes whenever the data changes
+    pub version: String,
+}
+
+pub fn read_cities() -> Result<Vec<City>, String> {
+    read_cities_from(&get_data_out_dir())
+}
+
+/// Reads all shards from `dir`. Returns a readable error if any shard is missing or malformed.
+///
+/// ```
+/// let err = common::city_csv::read_cities_from(std::path::Path::new("/no/such/dir")).unwrap_err();
+/// assert!(err.starts_with("Missing 32 of 32 city shards"));
+/// ```
+pub fn read_cities_from(dir: &Path) -> Result<Vec<City>, String> {
+    read_dataset_from(dir).map(|dataset| dataset.cities)
+}
 
-    eprintln!("Written totally {:.1} MB to {} shards", (total_size as f64) / 1024.0 / 1024.0, actual_shards_num);
+/// Cheap fingerprint of the shards' sizes and modification times, to notice that they were rewritten
+/// without reading them. None if some shard is missing.
+///
+/// ```
+/// assert_eq!(None, common::city_csv::shards_stamp_from(std::path::Path::new("/no/such/dir")));
+/// ```
+pub fn shards_stamp_from(dir: &Path) -> Option<u64> {
+    let mut hasher = DefaultHasher::new();
+    for shard in 0..EXPECTED_SHARDS_NUM {
+        let metadata = std::fs::metadata(dir.join(get_file_name(shard))).ok()?;
+        metadata.len().hash(&mut hasher);
+        metadata.modified().ok().hash(&mut hasher);
+    }
+    Some(hasher.finish())
+}
+
+pub fn shards_stamp() -> Option<u64> {
+    shards_stamp_from(&get_data_out_dir())
 }
 
-pub fn read_cities() -> Vec<City> {
+pub fn read_dataset() -> Result<Dataset, String> {
+    read_dataset_from(&get_data_out_dir())
+}
+
+pub fn read_dataset_from(dir: &Path) -> Result<Dataset, String> {
     let started = std::time::Instant::now();
 
-    let cities = (0..EXPECTED_SHARDS_NUM).into_par_iter()
-        .flat_map(|shard| {
-            let path = get_data_out_dir().join(get_file_name(shard));
-            let file_rd = std::io::BufReader::new(File::open(&path).unwrap());
+    let missing = (0..EXPECTED_SHARDS_NUM)
+        .map(get_file_name)
+        .filter(|file_name| !dir.join(file_name).is_file())
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        return Err(format!(
+            "Missing {} of {} city shards in {:?} (first: {}). Run preprocessing, or set {} to the directory containing the shards",
+            missing.len(), EXPECTED_SHARDS_NUM, dir, missing[0], DATA_DIR_ENV,
+        ));
+    }
+
+    let shards = (0..EXPECTED_SHARDS_NUM).into_par_iter()
+        .map(|shard| {
+            let path = dir.join(get_file_name(shard));
+            let bytes = std::fs::read(&path).map_err(|e| format!("Cannot read {:?}: {}", path, e))?;
+
+            let mut hasher = DefaultHasher::new();
+            bytes.hash(&mut hasher);
 
             let mut reader = csv::ReaderBuilder::new()
                 .has_headers(false)
                 .delimiter(b'\t')
-                .from_reader(file_rd);
+                .from_reader(bytes.as_slice());
             reader.deserialize::<CityCsvFriendly>()
-                .map(|res| City::from(res.unwrap()))
-                .collect::<Vec<_>>()
+                .map(|res| res
+                    .map(City::from)
+                    .map_err(|e| format!("Cannot read {:?}: {}", path, e))
+                )
+                .collect::<Result<Vec<_>, _>>()
+                .map(|cities| (cities, hasher.finish()))
         })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut version_hasher = DefaultHasher::new();
+    shards.iter().for_each(|(_, hash)| version_hasher.write_u64(*hash));
+    let version = format!("{:016x}", version_hasher.finish());
+
+    let cities = shards.into_iter()
+        .flat_map(|(cities, _)| cities)
         .collect::<Vec<_>>();
 
-    eprintln!("From {:?} shards loaded {} cities in {} ms", EXPECTED_SHARDS_NUM, cities.len(), started.elapsed().as_millis());
-    cities
+    tracing::info!(shards = EXPECTED_SHARDS_NUM, ?dir, cities = cities.len(), %version, elapsed_ms = started.elapsed().as_millis() as u64, "Loaded cities");
+    Ok(Dataset { cities, version })
 }
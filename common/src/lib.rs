@@ -1,4 +1,5 @@
 pub mod city;
 pub mod city_csv;
 pub mod csv_friendly_test;
+pub mod logging;
 pub mod util;
\ No newline at end of file
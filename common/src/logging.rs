@@ -0,0 +1,26 @@
+use std::io::IsTerminal;
+use tracing_subscriber::EnvFilter;
+
+/// Filter directives, e.g. `debug` or `info,backend=debug`, see `tracing_subscriber::EnvFilter`
+pub const LOG_ENV: &str = "SOMEWHERE_LIKE_LOG";
+/// `text` (the default) or `json`
+pub const LOG_FORMAT_ENV: &str = "SOMEWHERE_LIKE_LOG_FORMAT";
+
+/// Installs the global subscriber writing to stderr. Binaries call it first thing in `main`.
+pub fn init_logging() {
+    let filter = EnvFilter::try_from_env(LOG_ENV).unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::stderr().is_terminal());
+
+    match std::env::var(LOG_FORMAT_ENV).as_deref() {
+        // All spans, so that events in a nested span still have the request id
+        Ok("json") => builder.json().with_current_span(false).with_span_list(true).init(),
+        Ok("text") | Err(_) => builder.init(),
+        Ok(other) => {
+            builder.init();
+            tracing::warn!("Unknown {} \"{}\", expected text or json", LOG_FORMAT_ENV, other);
+        },
+    }
+}
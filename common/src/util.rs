@@ -1,40 +1,48 @@
-use std::{env::current_dir, path::PathBuf};
+use std::path::{Path, PathBuf};
 
 use num_traits::Float;
 
-/// Returns path to `data_in` directory, assuming the current directory is somewhere inside the project.
+pub const DATA_DIR_ENV: &str = "SOMEWHERE_LIKE_DATA_DIR";
+pub const DATA_IN_DIR_ENV: &str = "SOMEWHERE_LIKE_DATA_IN_DIR";
+
+/// Returns path to the preprocessing input directory: `SOMEWHERE_LIKE_DATA_IN_DIR` if set,
+/// otherwise `data-in` in the project.
 /// ```
-/// assert!(common::util::get_data_in_dir().to_str().unwrap().ends_with("data-in"));
+/// if std::env::var_os(common::util::DATA_IN_DIR_ENV).is_none() {
+///     assert!(common::util::get_data_in_dir().to_str().unwrap().ends_with("data-in"));
+/// }
 /// ```
 pub fn get_data_in_dir() -> PathBuf {
-    get_project_dir().join("data-in")
+    env_dir(DATA_IN_DIR_ENV).unwrap_or_else(|| get_project_dir().join("data-in"))
 }
 
-/// Returns path to `data_out` directory, assuming the current directory is somewhere inside the project.
+/// Returns path to the directory with city shards: `SOMEWHERE_LIKE_DATA_DIR` if set,
+/// otherwise `data-out` in the project.
 /// ```
-/// assert!(common::util::get_data_out_dir().to_str().unwrap().ends_with("data-out"));
+/// if std::env::var_os(common::util::DATA_DIR_ENV).is_none() {
+///     assert!(common::util::get_data_out_dir().to_str().unwrap().ends_with("data-out"));
+/// }
 /// ```
 pub fn get_data_out_dir() -> PathBuf {
-    get_project_dir().join("data-out")
+    env_dir(DATA_DIR_ENV).unwrap_or_else(|| get_project_dir().join("data-out"))
 }
 
-fn get_project_dir() -> PathBuf {
-    let current_dir = current_dir().unwrap();
+fn env_dir(name: &str) -> Option<PathBuf> {
+    std::env::var_os(name)
+        .filter(|it| !it.is_empty())
+        .map(PathBuf::from)
+}
 
-    let mut dir = current_dir.as_path();
-    loop {
-        if dir.file_name().unwrap() == "somewhere-like" {
-            break dir.to_path_buf();
-        }
-        dir = dir.parent().expect(&format!("Project root dir not found in: {:?}", current_dir));
-    }
+/// The workspace root this crate was built in
+fn get_project_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).parent().unwrap().to_path_buf()
 }
 
-pub fn eprintln_memory_usage() {
+pub fn log_memory_usage() {
     let mut sys = sysinfo::System::new_all();
     sys.refresh_all();
     let mem_bytes = sys.process(sysinfo::get_current_pid().unwrap()).unwrap().memory();
-    eprintln!("Using {} MB of RAM", (mem_bytes as f64) / 1024.0 / 1024.0);
+    tracing::info!(memory_mb = (mem_bytes as f64) / 1024.0 / 1024.0, "Memory usage");
 }
 
 /// Rounds to 1 decimal place and checks it's finite.
@@ -1,4 +1,4 @@
-use common::{city::{City, CityClimate}, city_csv::write_cities, util::{eprintln_memory_usage, round_0_1_and_assert_finite}};
+use common::{city::{City, CityClimate}, city_csv::write_cities, logging::init_logging, util::{log_memory_usage, round_0_1_and_assert_finite}};
 
 mod terra_climate;
 use rayon::prelude::*;
@@ -8,6 +8,7 @@ mod geonames;
 use geonames::{read_admin_codes, read_geonames_cities, read_geonames_country_names, GeonamesCity};
 
 fn main() {
+    init_logging();
     let started = std::time::Instant::now();
 
     let geonames_cities = read_geonames_cities();
@@ -35,10 +36,13 @@ fn main() {
         })
         .collect();
 
-    eprintln_memory_usage();
+    log_memory_usage();
     let cities_len = cities.len();
-    write_cities(cities);
-    eprintln!("Done {} cities in {:.2} sec", cities_len, started.elapsed().as_secs_f32());
+    if let Err(msg) = write_cities(cities) {
+        tracing::error!("{}", msg);
+        std::process::exit(1);
+    }
+    tracing::info!(cities = cities_len, elapsed_sec = started.elapsed().as_secs_f32(), "Done");
 }
 
 struct AllTerraClimate {
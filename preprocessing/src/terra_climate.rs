@@ -65,7 +65,7 @@ impl<T: TerraVal> TerraClimateData<T> {
             var_values,
         };
 
-        eprintln!("{:?}", terra_climate_data);
+        tracing::debug!(?terra_climate_data, "Read TerraClimate variable");
 
         terra_climate_data
     }
@@ -78,7 +78,7 @@ impl<T: TerraVal> TerraClimateData<T> {
         for month in 0..12 {
             let val = self.get_closest_value(month, lat_index, lon_index);
             if val.is_none() {
-                eprintln!("{} at {:?}: not found \"{}\"", city, (lat, lon), self.var_name);
+                tracing::warn!(city, lat, lon, var_name = %self.var_name, "Climate value not found");
                 return None;
             }
             monthly_values[month] = val.unwrap();
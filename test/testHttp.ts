@@ -1,7 +1,7 @@
 import assert from 'node:assert'
 import { spawn } from 'node:child_process'
 import { test } from 'uvu'
-import { fetchApi } from '../app/api.ts'
+import { fetchApi, fetchApiBatch } from '../app/api.ts'
 
 const subprocess = spawn('cargo', ['run', '-p', 'backend', '--release', '--bin', 'http'])
 
@@ -111,8 +111,12 @@ test('climate simple', async () => {
     assertMonthlyWithin(firstItem.city.climate.wsMonthly, 2, 4)
 })
 
-function assertMonthlyWithin(actual: number[], min: number, max: number) {
-    assert.equal(actual.length, 12)
+function assertMonthlyWithin(monthly: (number | null)[], min: number, max: number) {
+    assert.equal(monthly.length, 12)
+    const actual = monthly.map(it => {
+        assert(it !== null, 'Missing monthly value')
+        return it
+    })
 
     const actualMin = actual.reduce((a, b) => a < b ? a : b)
     const actualMax = actual.reduce((a, b) => a > b ? a : b)
@@ -155,5 +159,67 @@ test('climate search pages consistency', async () => {
     assert.deepStrictEqual([...page1.items, ...page2.items], page12.items)
 })
 
+test('get cities', async () => {
+    const single = await fetchApi({
+        command: 'getCity',
+        id: 14823, // Munich
+    })
+    assert.equal(single.id, 14823)
+    assert.equal(single.city.names[0], 'Munich')
+    assertMonthlyWithin(single.city.climate.tmaxMonthly, 4, 26)
+
+    const many = await fetchApi({
+        command: 'getCities',
+        ids: [16709, 14823],
+    })
+    assert.deepStrictEqual(many.items.map(it => it.city.names[0]), ['Copenhagen', 'Munich'])
+})
+
+test('batch', async () => {
+    const [search, error, city] = await fetchApiBatch([
+        { command: 'searchCity', query: 'Tokyo', maxItems: 1 },
+        { command: 'getCity', id: 99999999 },
+        { command: 'getCity', id: 14823 },
+    ])
+
+    assert('items' in search && search.items[0].name === 'Tokyo')
+    assert('error' in error && error.error.code === 'cityNotFound')
+    assert('city' in city && city.city.names[0] === 'Munich')
+})
+
+test('errors', async () => {
+    const post = (body: string) => fetch('http://localhost:3001', { method: 'POST', body })
+
+    const unknownField = await post(JSON.stringify({ command: 'searchCity', query: 'x', maxitems: 1 }))
+    assert.equal(unknownField.status, 400)
+    assert.deepStrictEqual((await unknownField.json()).error.field, 'maxitems')
+
+    const notFound = await post(JSON.stringify({ command: 'searchClimate', cityId: 99999999 }))
+    assert.equal(notFound.status, 404)
+    assert.equal((await notFound.json()).error.code, 'cityNotFound')
+})
+
+test('GET routes', async () => {
+    const search = await fetch('http://localhost:3001/cities?q=Tokyo&max=1')
+    assert.equal(search.status, 200)
+    const etag = search.headers.get('ETag')
+    assert(etag)
+    assert.equal((await search.json()).items[0].name, 'Tokyo')
+
+    const notModified = await fetch('http://localhost:3001/cities?q=Tokyo&max=1', {
+        headers: {'If-None-Match': etag},
+    })
+    assert.equal(notModified.status, 304)
+
+    const similar = await (await fetch('http://localhost:3001/cities/14823/similar?max=2')).json()
+    assert.equal(similar.items[0].city.names[0], 'Munich')
+
+    const city = await (await fetch('http://localhost:3001/cities/14823')).json()
+    assert.equal(city.command, 'getCity')
+    assert.equal(city.city.names[0], 'Munich')
+
+    assert.equal((await fetch('http://localhost:3001/cities/99999999')).status, 404)
+})
+
 test.after(() => void subprocess.kill())
 test.run()